@@ -0,0 +1,113 @@
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use download::Download;
+use ipc::IPC;
+use peer_pool::PeerPool;
+use tracker::{Event, Tracker};
+
+pub struct Announcer {
+    tx: Sender<IPC>,
+    thread: JoinHandle<()>,
+}
+
+impl Announcer {
+    pub fn start(tracker: Tracker, our_peer_id: String, listener_port: u16, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>) -> Announcer {
+        // register with Download so we hear about the download completing
+        let (tx, rx) = channel::<IPC>();
+        {
+            let mut download = download_mutex.lock().unwrap();
+            download.register_channel(tx.clone());
+        }
+
+        let thread = thread::spawn(move || {
+            let mut announce_loop = AnnounceLoop {
+                tracker: tracker,
+                our_peer_id: our_peer_id,
+                listener_port: listener_port,
+                download_mutex: download_mutex,
+                peer_pool: peer_pool,
+            };
+            announce_loop.run(rx);
+        });
+
+        Announcer {
+            tx: tx,
+            thread: thread,
+        }
+    }
+
+    // tell the trackers we're going away, and wait for that to finish
+    pub fn stop(self) {
+        // if the whole download was shut down, the announcer has already heard about it and stopped
+        match self.tx.send(IPC::Shutdown) {
+            Ok(_) | Err(_) => {}
+        }
+        match self.thread.join() {
+            Ok(_) => {},
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+}
+
+struct AnnounceLoop {
+    tracker: Tracker,
+    our_peer_id: String,
+    listener_port: u16,
+    download_mutex: Arc<Mutex<Download>>,
+    peer_pool: Arc<Mutex<PeerPool>>,
+}
+
+impl AnnounceLoop {
+    fn run(&mut self, rx: Receiver<IPC>) {
+        // trackers can take a while to answer (or never do), so the first announce happens here rather than holding up
+        // the rest of the startup
+        self.announce(Event::Started);
+
+        let mut next_announce = Instant::now() + self.interval();
+        loop {
+            let now = Instant::now();
+            let timeout = if next_announce > now { next_announce - now } else { Duration::from_secs(0) };
+
+            match rx.recv_timeout(timeout) {
+                Ok(IPC::DownloadComplete) => {
+                    self.announce(Event::Completed);
+                    next_announce = Instant::now() + self.interval();
+                },
+                Ok(IPC::Shutdown) => {
+                    self.announce(Event::Stopped);
+                    return;
+                },
+                Ok(_) => {},
+                Err(RecvTimeoutError::Timeout) => {
+                    self.announce(Event::Regular);
+                    next_announce = Instant::now() + self.interval();
+                },
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        }
+    }
+
+    fn announce(&mut self, event: Event) {
+        let stats = {
+            let download = self.download_mutex.lock().unwrap();
+            download.stats()
+        };
+
+        match self.tracker.get_peers(&self.our_peer_id, self.listener_port, stats, event) {
+            Ok(peers) => {
+                println!("Announced {:?}, found {} peers", event, peers.len());
+                let mut peer_pool = self.peer_pool.lock().unwrap();
+                peer_pool.add(peers);
+            },
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.tracker.interval() as u64)
+    }
+}
@@ -0,0 +1,155 @@
+use rand;
+use rand::Rng;
+use std::thread;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use download::Download;
+use ipc::IPC;
+
+// how many peers we upload to at once, including the optimistic unchoke
+const UNCHOKE_SLOTS: usize = 4;
+
+// rechoke every 10 seconds, and pick a new optimistic unchoke every third time
+const RECHOKE_INTERVAL_SECS: u64 = 10;
+const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+// decides which peers we upload to, preferring the ones that give us the most in return (tit-for-tat)
+pub struct Choker {
+    peers: HashMap<usize, ChokerPeer>,
+    next_id: usize,
+    optimistic: Option<usize>,
+    round: u32,
+}
+
+struct ChokerPeer {
+    tx: Sender<IPC>,
+    is_interested: bool,
+    is_unchoked: bool,
+    downloaded: u64,
+    uploaded: u64,
+}
+
+impl Choker {
+    pub fn new() -> Choker {
+        Choker {
+            peers: HashMap::new(),
+            next_id: 0,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    // returns the id the peer connection uses to report back to us. every peer starts off choked
+    pub fn register(&mut self, tx: Sender<IPC>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.peers.insert(id, ChokerPeer {
+            tx: tx,
+            is_interested: false,
+            is_unchoked: false,
+            downloaded: 0,
+            uploaded: 0,
+        });
+        id
+    }
+
+    pub fn unregister(&mut self, id: usize) {
+        self.peers.remove(&id);
+        if self.optimistic == Some(id) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn interested(&mut self, id: usize, is_interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.is_interested = is_interested;
+        }
+
+        // don't make new peers wait for the next rechoke if there's a free slot
+        let unchoked = self.peers.values().filter(|p| p.is_unchoked).count();
+        if is_interested && unchoked < UNCHOKE_SLOTS {
+            self.set_unchoked(id, true);
+        }
+    }
+
+    pub fn downloaded(&mut self, id: usize, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.downloaded += bytes;
+        }
+    }
+
+    pub fn uploaded(&mut self, id: usize, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    // rank interested peers by how much they sent us while we're leeching, or by how much we sent them while we're
+    // seeding (so that we spread the data to the peers that can take it fastest)
+    fn rechoke(&mut self, seeding: bool) {
+        let mut interested: Vec<(usize, u64)> = self.peers.iter()
+            .filter(|&(_, p)| p.is_interested)
+            .map(|(&id, p)| (id, if seeding { p.uploaded } else { p.downloaded }))
+            .collect();
+        interested.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut unchoke: Vec<usize> = interested.iter().take(UNCHOKE_SLOTS - 1).map(|&(id, _)| id).collect();
+
+        // give a random peer a chance to show us what it can do, so we can find better peers than the ones we have
+        self.round += 1;
+        let optimistic_expired = self.round % OPTIMISTIC_UNCHOKE_ROUNDS == 0;
+        let optimistic_valid = match self.optimistic {
+            Some(id) => !unchoke.contains(&id) && self.peers.get(&id).map_or(false, |p| p.is_interested),
+            None => false
+        };
+        if optimistic_expired || !optimistic_valid {
+            let candidates: Vec<usize> = interested.iter().map(|&(id, _)| id).filter(|id| !unchoke.contains(id)).collect();
+            self.optimistic = rand::thread_rng().choose(&candidates).cloned();
+        }
+        if let Some(id) = self.optimistic {
+            unchoke.push(id);
+        }
+
+        let ids: Vec<usize> = self.peers.keys().cloned().collect();
+        for id in ids {
+            self.set_unchoked(id, unchoke.contains(&id));
+        }
+
+        // rates are measured over each rechoke interval
+        for peer in self.peers.values_mut() {
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+    }
+
+    fn set_unchoked(&mut self, id: usize, unchoked: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            if peer.is_unchoked != unchoked {
+                peer.is_unchoked = unchoked;
+                let ipc = if unchoked { IPC::Unchoke } else { IPC::Choke };
+                match peer.tx.send(ipc) {
+                    Ok(_) => {},
+                    Err(_) => {} // the peer is disconnecting, and will unregister itself
+                }
+            }
+        }
+    }
+}
+
+pub fn start(choker_mutex: Arc<Mutex<Choker>>, download_mutex: Arc<Mutex<Download>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(RECHOKE_INTERVAL_SECS));
+            let seeding = {
+                let download = download_mutex.lock().unwrap();
+                download.is_complete()
+            };
+            let mut choker = choker_mutex.lock().unwrap();
+            choker.rechoke(seeding);
+        }
+    })
+}
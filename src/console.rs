@@ -0,0 +1,71 @@
+use std::{io, thread};
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+use rate_limiter;
+use rate_limiter::{RateLimiter, RateLimits};
+use read_cache::ReadCache;
+
+const HELP: &'static str = "Commands: [torrent] up <KiB/s>, [torrent] down <KiB/s>, limits (0 means unlimited), cache, quit";
+
+// read commands from stdin, so the rate limits can be changed (and the read cache checked on) while we're running.
+// quitting is signalled on quit_tx
+pub fn start(global: RateLimits, torrent: RateLimits, read_cache: Arc<Mutex<ReadCache>>, quit_tx: Sender<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(ref l) if l.trim() == "quit" => {
+                    match quit_tx.send(()) {
+                        Ok(_) => {},
+                        Err(e) => println!("Error: {:?}", e)
+                    }
+                    return
+                },
+                Ok(l) => run_command(&l, &global, &torrent, &read_cache),
+                Err(_) => return
+            }
+        }
+    })
+}
+
+fn run_command(line: &str, global: &RateLimits, torrent: &RateLimits, read_cache: &Arc<Mutex<ReadCache>>) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    // commands apply to every torrent, unless they start with "torrent"
+    let (limits, scope, words) = match words.first() {
+        Some(&"torrent") => (torrent, "Torrent", &words[1..]),
+        _ => (global, "Global", &words[..])
+    };
+
+    match (words.get(0), words.get(1)) {
+        (Some(&"up"), Some(rate)) => set_rate(&limits.upload, rate, scope, "upload"),
+        (Some(&"down"), Some(rate)) => set_rate(&limits.download, rate, scope, "download"),
+        (Some(&"limits"), None) => {
+            for &(name, limits) in [("Global", global), ("Torrent", torrent)].iter() {
+                let upload = limits.upload.lock().unwrap().rate();
+                let download = limits.download.lock().unwrap().rate();
+                println!("{} limits: upload {}, download {}", name, rate_limiter::format_rate(upload), rate_limiter::format_rate(download));
+            }
+        },
+        (Some(&"cache"), None) => {
+            let stats = read_cache.lock().unwrap().stats();
+            println!("Read cache: {} of {} MiB used, {} hits, {} misses ({:.1}% hit rate)",
+                     stats.size / 1024 / 1024, stats.capacity / 1024 / 1024, stats.hits, stats.misses, stats.hit_rate());
+        },
+        (None, _) => {},
+        _ => println!("{}", HELP)
+    }
+}
+
+fn set_rate(limiter: &Arc<Mutex<RateLimiter>>, rate: &str, scope: &str, direction: &str) {
+    match rate_limiter::parse_rate(rate) {
+        Ok(r) => {
+            limiter.lock().unwrap().set_rate(r);
+            println!("{} {} limit set to {}", scope, direction, rate_limiter::format_rate(r));
+        },
+        Err(e) => println!("Bad rate limit {}: {:?}", rate, e)
+    }
+}
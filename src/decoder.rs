@@ -3,6 +3,15 @@
 use bencode;
 use std::{convert, io};
 
+macro_rules! try_opt {
+    ($e:expr) => (
+        match $e {
+            Some(v) => v,
+            None => return None
+        }
+    )
+}
+
 #[macro_export]
 macro_rules! get_field_with_default {
     ($m:expr, $field:expr, $default:expr) => (
@@ -54,13 +63,75 @@ macro_rules! get_field_as_bytes {
     )
 }
 
+#[macro_export]
+macro_rules! get_field_as_list {
+    ($m:expr, $field:expr) => (
+        match get_raw_field!($m, $field) {
+            &Bencode::List(ref v) => v,
+            _ => return Err(decoder::Error::NotAList)
+        }
+    )
+}
+
+// lists and dicts nested deeper than this are refused, since the bencode parser recurses into them and the data comes
+// from strangers
+const MAX_DEPTH: usize = 64;
+
+// find the length of the bencoded value at the start of the buffer, for when it's followed by other data. returns None
+// if it isn't valid, or is nested too deeply
+pub fn bencoded_length(bytes: &[u8]) -> Option<usize> {
+    // keep count of the lists and dicts we're inside, rather than recursing into them
+    let mut pos = 0;
+    let mut depth = 0;
+    loop {
+        match bytes.get(pos) {
+            Some(&b'i') => pos += try_opt!(bytes[pos..].iter().position(|&b| b == b'e')) + 1,
+            Some(&b'l') | Some(&b'd') => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return None;
+                }
+                pos += 1;
+            },
+            Some(&b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            },
+            Some(&(b'0'..=b'9')) => {
+                let colon = pos + try_opt!(bytes[pos..].iter().position(|&b| b == b':'));
+                let length: usize = try_opt!(String::from_utf8_lossy(&bytes[pos..colon]).parse().ok());
+                pos = try_opt!((colon + 1).checked_add(length));
+                if pos > bytes.len() {
+                    return None;
+                }
+            },
+            _ => return None
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// parse bencoded data from the network, refusing anything that's nested too deeply to parse safely
+pub fn parse_untrusted(bytes: &[u8]) -> Result<bencode::Bencode, Error> {
+    match bencoded_length(bytes) {
+        Some(_) => Ok(try!(bencode::from_buffer(bytes))),
+        None => Err(Error::Malformed)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
     DecodingError(bencode::streaming::Error),
+    Malformed,
     NotADict,
     NotAByteString,
+    NotAList,
     DoesntContain(&'static str),
+    UnsafePath(String),
+    UnresolvableHost(String),
     NotANumber(bencode::NumFromBencodeError),
     NotAString(bencode::StringFromBencodeError),
 }
@@ -88,3 +159,44 @@ impl convert::From<bencode::StringFromBencodeError> for Error {
         Error::NotAString(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        assert_eq!(bencoded_length(b"i42e"), Some(4));
+        assert_eq!(bencoded_length(b"4:spamtrailing"), Some(6));
+        assert_eq!(bencoded_length(b"l4:spami42ee"), Some(12));
+        assert_eq!(bencoded_length(b"d1:ad2:id3:abce1:q4:pinge..."), Some(25));
+        assert_eq!(bencoded_length(b"lee"), Some(2));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(bencoded_length(b""), None);
+        assert_eq!(bencoded_length(b"i42"), None);
+        assert_eq!(bencoded_length(b"5:spam"), None);
+        assert_eq!(bencoded_length(b"l4:spam"), None);
+        assert_eq!(bencoded_length(b"e"), None);
+    }
+
+    #[test]
+    fn huge_string_length() {
+        assert_eq!(bencoded_length(b"18446744073709551615:x"), None);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut deep = vec![b'l'; MAX_DEPTH + 1];
+        deep.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert_eq!(bencoded_length(&deep), None);
+        assert!(parse_untrusted(&deep).is_err());
+
+        let mut ok = vec![b'l'; MAX_DEPTH];
+        ok.extend(vec![b'e'; MAX_DEPTH]);
+        assert_eq!(bencoded_length(&ok), Some(MAX_DEPTH * 2));
+        assert!(parse_untrusted(&ok).is_ok());
+    }
+}
@@ -0,0 +1,721 @@
+use bencode;
+use bencode::Bencode;
+use bencode::util::ByteString;
+use rand;
+use rand::Rng;
+use std::{convert, io, thread};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use decoder;
+use hash::{calculate_sha1, Sha1};
+use ipc::IPC;
+use krpc;
+use krpc::{Body, Message, NodeId, NodeInfo, Query, Response, NODE_ID_LENGTH};
+use peer_pool::PeerPool;
+use routing_table::{distance, RoutingTable, K};
+use tracker_response::Peer;
+
+pub const BOOTSTRAP_NODES: [&'static str; 3] = ["router.bittorrent.com:6881", "dht.transmissionbt.com:6881", "router.utorrent.com:6881"];
+
+// our id and the nodes we know about are saved here, so we don't have to bootstrap from scratch next time
+const NODE_FILE: &'static str = "dht_nodes";
+
+// how many queries a lookup keeps in flight at once
+const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT_SECS: u64 = 5;
+const LOOKUP_INTERVAL_SECS: u64 = 15 * 60;
+const SAVE_INTERVAL_SECS: u64 = 5 * 60;
+
+// how often we check for questionable nodes to ping and stale buckets to refresh
+const REFRESH_INTERVAL_SECS: u64 = 60;
+
+// tokens stay valid for up to 10 minutes, by accepting the current and the previous secret
+const SECRET_LIFETIME_SECS: u64 = 5 * 60;
+
+// peers that announce to us are forgotten if they don't announce again
+const PEER_LIFETIME_SECS: u64 = 30 * 60;
+const MAX_PEERS_PER_RESPONSE: usize = 50;
+
+// anyone can announce to us, so there's a limit on how much we remember. when a torrent has too many peers, the one
+// that announced longest ago makes way
+const MAX_TORRENTS: usize = 1000;
+const MAX_PEERS_PER_TORRENT: usize = 100;
+
+const POLL_INTERVAL_MILLIS: u64 = 250;
+const MAX_PACKET_SIZE: usize = 65536;
+
+pub struct Dht {
+    tx: Sender<IPC>,
+    thread: JoinHandle<()>,
+}
+
+impl Dht {
+    // join the DHT on the given UDP port, look up peers for the torrent, and announce that we're downloading it
+    pub fn start(port: u16, info_hash: Sha1, listener_port: u16, bootstrap_nodes: Vec<String>, peer_pool: Arc<Mutex<PeerPool>>) -> Result<Dht, Error> {
+        let socket = try!(UdpSocket::bind(("0.0.0.0", port)));
+        try!(socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MILLIS))));
+
+        // pick up where we left off last time, if we can
+        let (our_id, saved_nodes) = match load_nodes(Path::new(NODE_FILE)) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => (random_id(), vec![]),
+            Err(e) => {
+                println!("Ignoring saved DHT nodes: {:?}", e);
+                (random_id(), vec![])
+            }
+        };
+        println!("Joining the DHT with {} saved nodes", saved_nodes.len());
+
+        let (tx, rx) = channel::<IPC>();
+        let thread = thread::spawn(move || {
+            let mut node = DhtNode::new(socket, our_id, info_hash, listener_port, resolve(&bootstrap_nodes), peer_pool);
+            node.run(rx, saved_nodes);
+        });
+
+        Ok(Dht {
+            tx: tx,
+            thread: thread,
+        })
+    }
+
+    // save the node table, and wait for that to finish
+    pub fn stop(self) {
+        match self.tx.send(IPC::Shutdown) {
+            Ok(_) => {
+                match self.thread.join() {
+                    Ok(_) => {},
+                    Err(e) => println!("Error: {:?}", e)
+                }
+            },
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+}
+
+struct DhtNode {
+    socket: UdpSocket,
+    table: RoutingTable,
+    info_hash: Sha1,
+    listener_port: u16,
+    peer_pool: Arc<Mutex<PeerPool>>,
+    bootstrap_addrs: Vec<SocketAddr>,
+    pending: HashMap<Vec<u8>, PendingQuery>,
+    next_transaction_id: u16,
+    lookup: Option<Lookup>,
+    next_lookup: Instant,
+    secret: Vec<u8>,
+    previous_secret: Vec<u8>,
+    secret_changed_at: Instant,
+    peers: HashMap<Sha1, Vec<(SocketAddr, Instant)>>,
+    saved_at: Instant,
+    refreshed_at: Instant,
+}
+
+struct PendingQuery {
+    addr: SocketAddr,
+    kind: QueryKind,
+    sent_at: Instant,
+}
+
+#[derive(PartialEq)]
+enum QueryKind {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+}
+
+impl DhtNode {
+    fn new(socket: UdpSocket, our_id: NodeId, info_hash: Sha1, listener_port: u16, bootstrap_addrs: Vec<SocketAddr>, peer_pool: Arc<Mutex<PeerPool>>) -> DhtNode {
+        DhtNode {
+            socket: socket,
+            table: RoutingTable::new(our_id),
+            info_hash: info_hash,
+            listener_port: listener_port,
+            peer_pool: peer_pool,
+            bootstrap_addrs: bootstrap_addrs,
+            pending: HashMap::new(),
+            next_transaction_id: 0,
+            lookup: None,
+            next_lookup: Instant::now(),
+            secret: random_id(),
+            previous_secret: random_id(),
+            secret_changed_at: Instant::now(),
+            peers: HashMap::new(),
+            saved_at: Instant::now(),
+            refreshed_at: Instant::now(),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<IPC>, saved_nodes: Vec<NodeInfo>) {
+        self.start_lookup(saved_nodes);
+        self.refresh();
+
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            match rx.try_recv() {
+                Ok(IPC::Shutdown) | Err(TryRecvError::Disconnected) => break,
+                Ok(_) | Err(TryRecvError::Empty) => {}
+            }
+
+            self.poll(&mut buf);
+            self.tick();
+        }
+
+        self.save();
+    }
+
+    // handle the next packet, if one arrives in time
+    fn poll(&mut self, buf: &mut [u8]) {
+        match self.socket.recv_from(buf) {
+            Ok((size, from)) => self.receive(&buf[..size], from),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => println!("DHT error: {:?}", e)
+        }
+    }
+
+    fn receive(&mut self, bytes: &[u8], from: SocketAddr) {
+        // there's a lot of noise out there, so quietly drop anything we can't understand
+        let Message { transaction_id, sender_id, body } = match Message::parse(bytes) {
+            Ok(message) => message,
+            Err(_) => return
+        };
+
+        match body {
+            Body::Query(query) => {
+                if let Some(id) = sender_id {
+                    self.table.heard_from(NodeInfo { id: id, addr: from });
+                }
+                let reply = self.answer(transaction_id, query, from);
+                self.send(&reply, from);
+            },
+            Body::Response(response) => {
+                if let Some(id) = sender_id {
+                    self.handle_response(transaction_id, id, response, from);
+                }
+            },
+            Body::Error(code, message) => {
+                if self.pending.get(&transaction_id).map_or(false, |p| p.addr == from) {
+                    println!("DHT node {} returned error {}: {}", from, code, message);
+                    let pending = self.pending.remove(&transaction_id).unwrap();
+                    self.query_failed(pending);
+                }
+            }
+        }
+    }
+
+    fn answer(&mut self, transaction_id: Vec<u8>, query: Query, from: SocketAddr) -> Message {
+        let our_id = self.table.our_id().clone();
+        match query {
+            Query::Ping => Message::response(transaction_id, our_id, Response::empty()),
+            Query::FindNode(target) => {
+                let mut response = Response::empty();
+                response.nodes = self.table.closest(&target, K);
+                Message::response(transaction_id, our_id, response)
+            },
+            Query::GetPeers(info_hash) => {
+                // hand out a token, so they can announce to us later
+                let mut response = Response::empty();
+                response.token = Some(token(&self.secret, &from.ip()));
+                response.values = self.peers_for(&info_hash);
+                if response.values.len() == 0 {
+                    response.nodes = self.table.closest(&info_hash, K);
+                }
+                Message::response(transaction_id, our_id, response)
+            },
+            Query::AnnouncePeer { info_hash, port, implied_port, token: t } => {
+                if t != token(&self.secret, &from.ip()) && t != token(&self.previous_secret, &from.ip()) {
+                    return Message::error(transaction_id, krpc::ERROR_PROTOCOL, "Bad token");
+                }
+
+                // with implied_port, the peer is listening on the same port it sent this from (e.g. for uTP behind a NAT)
+                let addr = SocketAddr::new(from.ip(), if implied_port { from.port() } else { port });
+                if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_TORRENTS {
+                    return Message::error(transaction_id, krpc::ERROR_SERVER, "Too many torrents");
+                }
+                let peers = self.peers.entry(info_hash).or_insert(vec![]);
+                peers.retain(|&(a, _)| a != addr);
+                if peers.len() >= MAX_PEERS_PER_TORRENT {
+                    peers.remove(0);
+                }
+                peers.push((addr, Instant::now()));
+                Message::response(transaction_id, our_id, Response::empty())
+            },
+            Query::Unknown(_) => Message::error(transaction_id, krpc::ERROR_METHOD_UNKNOWN, "Method Unknown")
+        }
+    }
+
+    fn handle_response(&mut self, transaction_id: Vec<u8>, id: NodeId, response: Response, from: SocketAddr) {
+        // ignore responses we didn't ask for, including late ones for queries that timed out
+        if !self.pending.get(&transaction_id).map_or(false, |p| p.addr == from) {
+            return;
+        }
+        let pending = self.pending.remove(&transaction_id).unwrap();
+        self.table.heard_from(NodeInfo { id: id.clone(), addr: from });
+
+        // nodes we're told about only make it into the table once they answer a ping themselves
+        if pending.kind == QueryKind::FindNode {
+            let new_nodes: Vec<NodeInfo> = response.nodes.into_iter().filter(|n| self.table.has_room_for(&n.id)).take(K).collect();
+            for node in new_nodes.into_iter() {
+                self.send_query(node.addr, Query::Ping, QueryKind::Ping);
+            }
+            return;
+        }
+
+        if pending.kind == QueryKind::GetPeers {
+            let our_id = self.table.our_id().clone();
+            if let Some(ref mut lookup) = self.lookup {
+                lookup.responded(NodeInfo { id: id, addr: from }, response.token);
+                for node in response.nodes.into_iter().filter(|n| n.id != our_id) {
+                    lookup.add(node);
+                }
+            }
+
+            if response.values.len() > 0 {
+                let added = {
+                    let mut peer_pool = self.peer_pool.lock().unwrap();
+                    peer_pool.add(response.values)
+                };
+                if added > 0 {
+                    println!("Found {} peers via the DHT", added);
+                }
+            }
+        }
+    }
+
+    fn query_failed(&mut self, pending: PendingQuery) {
+        self.table.failed(&pending.addr);
+        if pending.kind == QueryKind::GetPeers {
+            if let Some(ref mut lookup) = self.lookup {
+                lookup.failed(&pending.addr);
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        // give up on nodes that didn't answer in time
+        let timeout = Duration::from_secs(QUERY_TIMEOUT_SECS);
+        let expired: Vec<Vec<u8>> = self.pending.iter().filter(|&(_, p)| p.sent_at.elapsed() >= timeout).map(|(t, _)| t.clone()).collect();
+        for transaction_id in expired.iter() {
+            let pending = self.pending.remove(transaction_id).unwrap();
+            self.query_failed(pending);
+        }
+
+        if self.secret_changed_at.elapsed() >= Duration::from_secs(SECRET_LIFETIME_SECS) {
+            self.previous_secret = self.secret.clone();
+            self.secret = random_id();
+            self.secret_changed_at = Instant::now();
+        }
+
+        let peer_lifetime = Duration::from_secs(PEER_LIFETIME_SECS);
+        for peers in self.peers.values_mut() {
+            peers.retain(|&(_, announced_at)| announced_at.elapsed() < peer_lifetime);
+        }
+        self.peers.retain(|_, peers| peers.len() > 0);
+
+        if self.lookup.is_none() && Instant::now() >= self.next_lookup {
+            self.start_lookup(vec![]);
+        }
+        self.advance_lookup();
+
+        if self.refreshed_at.elapsed() >= Duration::from_secs(REFRESH_INTERVAL_SECS) {
+            self.refresh();
+        }
+
+        if self.saved_at.elapsed() >= Duration::from_secs(SAVE_INTERVAL_SECS) {
+            self.save();
+        }
+    }
+
+    // keep the routing table healthy (BEP 5): ping nodes that have gone quiet, so the dead ones get dropped, and look
+    // for new nodes in buckets that haven't changed in a while. until the table fills up, keep looking for nodes close
+    // to us
+    fn refresh(&mut self) {
+        self.refreshed_at = Instant::now();
+
+        for node in self.table.questionable().into_iter() {
+            if !self.pending.values().any(|p| p.addr == node.addr) {
+                self.send_query(node.addr, Query::Ping, QueryKind::Ping);
+            }
+        }
+
+        let mut targets = self.table.refresh_targets();
+        if self.table.len() < K {
+            targets.push(self.table.our_id().clone());
+        }
+        for target in targets.into_iter() {
+            let mut addrs: Vec<SocketAddr> = self.table.closest(&target, ALPHA).into_iter().map(|n| n.addr).collect();
+            if addrs.len() < ALPHA {
+                addrs.extend(self.bootstrap_addrs.iter().cloned());
+            }
+            for addr in addrs.into_iter() {
+                self.send_query(addr, Query::FindNode(target.clone()), QueryKind::FindNode);
+            }
+        }
+    }
+
+    fn start_lookup(&mut self, extra_nodes: Vec<NodeInfo>) {
+        let mut lookup = Lookup::new();
+        for node in self.table.closest(&self.info_hash, K).into_iter().chain(extra_nodes.into_iter()) {
+            lookup.add(node);
+        }
+
+        // if we don't know enough nodes yet, ask the bootstrap nodes too
+        if self.table.len() < K {
+            for addr in self.bootstrap_addrs.clone().into_iter() {
+                let query = Query::GetPeers(self.info_hash.clone());
+                self.send_query(addr, query, QueryKind::GetPeers);
+            }
+        }
+
+        self.lookup = Some(lookup);
+    }
+
+    // query the closest nodes we haven't asked yet, until the closest K have all answered
+    fn advance_lookup(&mut self) {
+        let in_flight = self.pending.values().filter(|p| p.kind == QueryKind::GetPeers).count();
+        let to_query = match self.lookup {
+            Some(ref mut lookup) => lookup.next(&self.info_hash, in_flight),
+            None => return
+        };
+
+        if to_query.len() == 0 && in_flight == 0 {
+            self.finish_lookup();
+            return;
+        }
+
+        for node in to_query.into_iter() {
+            let query = Query::GetPeers(self.info_hash.clone());
+            self.send_query(node.addr, query, QueryKind::GetPeers);
+        }
+    }
+
+    // announce ourselves to the closest nodes that gave us a token
+    fn finish_lookup(&mut self) {
+        let lookup = match self.lookup.take() {
+            Some(lookup) => lookup,
+            None => return
+        };
+
+        let mut announced = 0;
+        for (addr, token) in lookup.closest_tokens().into_iter() {
+            let query = Query::AnnouncePeer {
+                info_hash: self.info_hash.clone(),
+                port: self.listener_port,
+                implied_port: false,
+                token: token,
+            };
+            if self.send_query(addr, query, QueryKind::AnnouncePeer) {
+                announced += 1;
+            }
+        }
+        println!("Announced to {} DHT nodes ({} nodes known)", announced, self.table.len());
+
+        self.next_lookup = Instant::now() + Duration::from_secs(LOOKUP_INTERVAL_SECS);
+    }
+
+    fn send_query(&mut self, addr: SocketAddr, query: Query, kind: QueryKind) -> bool {
+        let transaction_id = vec![(self.next_transaction_id >> 8) as u8, self.next_transaction_id as u8];
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+
+        let message = Message::query(transaction_id.clone(), self.table.our_id().clone(), query);
+        if !self.send(&message, addr) {
+            return false;
+        }
+        self.pending.insert(transaction_id, PendingQuery { addr: addr, kind: kind, sent_at: Instant::now() });
+        true
+    }
+
+    fn send(&self, message: &Message, addr: SocketAddr) -> bool {
+        let result = message.to_bytes().and_then(|bytes| self.socket.send_to(&bytes, addr));
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Error sending to DHT node {}: {:?}", addr, e);
+                false
+            }
+        }
+    }
+
+    fn peers_for(&self, info_hash: &[u8]) -> Vec<Peer> {
+        match self.peers.get(info_hash) {
+            Some(peers) => peers.iter().take(MAX_PEERS_PER_RESPONSE).map(|&(addr, _)| Peer::new(addr.ip(), addr.port())).collect(),
+            None => vec![]
+        }
+    }
+
+    fn save(&mut self) {
+        self.saved_at = Instant::now();
+        match save_nodes(Path::new(NODE_FILE), self.table.our_id(), &self.table.nodes()) {
+            Ok(_) => {},
+            Err(e) => println!("Error saving DHT nodes: {:?}", e)
+        }
+    }
+}
+
+// an iterative get_peers lookup, converging on the nodes closest to the info hash
+struct Lookup {
+    candidates: Vec<Candidate>,
+}
+
+struct Candidate {
+    node: NodeInfo,
+    state: CandidateState,
+    token: Option<Vec<u8>>,
+}
+
+#[derive(PartialEq)]
+enum CandidateState {
+    Unqueried,
+    Queried,
+    Responded,
+    Failed,
+}
+
+impl Lookup {
+    fn new() -> Lookup {
+        Lookup { candidates: vec![] }
+    }
+
+    fn add(&mut self, node: NodeInfo) {
+        if !self.candidates.iter().any(|c| c.node.id == node.id || c.node.addr == node.addr) {
+            self.candidates.push(Candidate { node: node, state: CandidateState::Unqueried, token: None });
+        }
+    }
+
+    // bootstrap nodes aren't candidates until they answer, since we don't know their ids
+    fn responded(&mut self, node: NodeInfo, token: Option<Vec<u8>>) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.node.addr == node.addr) {
+            candidate.node = node;
+            candidate.state = CandidateState::Responded;
+            candidate.token = token;
+            return;
+        }
+        self.candidates.push(Candidate { node: node, state: CandidateState::Responded, token: token });
+    }
+
+    fn failed(&mut self, addr: &SocketAddr) {
+        for candidate in self.candidates.iter_mut().filter(|c| c.node.addr == *addr) {
+            candidate.state = CandidateState::Failed;
+        }
+    }
+
+    // pick the next nodes to query, marking them as queried
+    fn next(&mut self, target: &[u8], in_flight: usize) -> Vec<NodeInfo> {
+        self.candidates.sort_by(|a, b| distance(&a.node.id, target).cmp(&distance(&b.node.id, target)));
+
+        let mut to_query = vec![];
+        for candidate in self.candidates.iter_mut().filter(|c| c.state != CandidateState::Failed).take(K) {
+            if in_flight + to_query.len() >= ALPHA {
+                break;
+            }
+            if candidate.state == CandidateState::Unqueried {
+                candidate.state = CandidateState::Queried;
+                to_query.push(candidate.node.clone());
+            }
+        }
+        to_query
+    }
+
+    fn closest_tokens(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.candidates.iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(K)
+            .filter_map(|c| c.token.as_ref().map(|t| (c.node.addr, t.clone())))
+            .collect()
+    }
+}
+
+// a token is tied to the IP address of the node that asked for it
+fn token(secret: &[u8], ip: &IpAddr) -> Vec<u8> {
+    let mut input = secret.to_owned();
+    match *ip {
+        IpAddr::V4(ip) => input.extend(ip.octets().iter().cloned()),
+        IpAddr::V6(ip) => input.extend(ip.octets().iter().cloned()),
+    }
+    calculate_sha1(&input)[0..8].to_owned()
+}
+
+fn random_id() -> NodeId {
+    let mut rng = rand::thread_rng();
+    (0..NODE_ID_LENGTH).map(|_| rng.gen()).collect()
+}
+
+fn resolve(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for host in hosts.iter() {
+        match host.to_socket_addrs() {
+            Ok(a) => addrs.extend(a.filter(|addr| addr.is_ipv4())),
+            Err(e) => println!("Couldn't resolve DHT bootstrap node {}: {:?}", host, e)
+        }
+    }
+    addrs
+}
+
+fn load_nodes(path: &Path) -> Result<Option<(NodeId, Vec<NodeInfo>)>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut f = try!(File::open(path));
+    let mut bytes = Vec::new();
+    try!(f.read_to_end(&mut bytes));
+    let saved = try!(parse_nodes(&bytes));
+    Ok(Some(saved))
+}
+
+fn parse_nodes(bytes: &[u8]) -> Result<(NodeId, Vec<NodeInfo>), decoder::Error> {
+    match try!(bencode::from_buffer(bytes)) {
+        Bencode::Dict(ref m) => {
+            let our_id = get_field_as_bytes!(m, "id");
+            if our_id.len() != NODE_ID_LENGTH {
+                return Err(decoder::Error::DoesntContain("id"));
+            }
+            let nodes = krpc::parse_compact_nodes(&get_field_as_bytes!(m, "nodes"));
+            Ok((our_id, nodes))
+        },
+        _ => Err(decoder::Error::NotADict)
+    }
+}
+
+fn save_nodes(path: &Path, our_id: &NodeId, nodes: &[NodeInfo]) -> Result<(), Error> {
+    let mut m = BTreeMap::new();
+    m.insert(ByteString::from_str("id"), Bencode::ByteString(our_id.clone()));
+    m.insert(ByteString::from_str("nodes"), Bencode::ByteString(krpc::compact_nodes(nodes)));
+    let bytes = try!(Bencode::Dict(m).to_bytes());
+
+    let mut f = try!(File::create(path));
+    try!(f.write_all(&bytes));
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    DecoderError(decoder::Error),
+    IoError(io::Error),
+}
+
+impl convert::From<decoder::Error> for Error {
+    fn from(err: decoder::Error) -> Error {
+        Error::DecoderError(err)
+    }
+}
+
+impl convert::From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::{Duration, Instant};
+
+    use krpc::{Message, Query};
+    use peer_pool::PeerPool;
+    use tracker_response::Peer;
+    use super::*;
+
+    // a node on localhost, and the channel that peers it finds are passed along on
+    fn start_node(info_hash: &Sha1, listener_port: u16, bootstrap_addrs: Vec<SocketAddr>) -> (DhtNode, Receiver<Peer>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let (tx, rx) = channel();
+        let peer_pool = Arc::new(Mutex::new(PeerPool::new(tx)));
+        let node = DhtNode::new(socket, random_id(), info_hash.clone(), listener_port, bootstrap_addrs, peer_pool);
+        (node, rx)
+    }
+
+    fn addr(node: &DhtNode) -> SocketAddr {
+        node.socket.local_addr().unwrap()
+    }
+
+    // let the nodes talk to each other for a while
+    fn poll_all(nodes: &mut [&mut DhtNode], millis: u64) {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let deadline = Instant::now() + Duration::from_millis(millis);
+        while Instant::now() < deadline {
+            for node in nodes.iter_mut() {
+                node.poll(&mut buf);
+                node.tick();
+            }
+        }
+    }
+
+    fn announce(node: &mut DhtNode, info_hash: &Sha1, from: SocketAddr) -> Message {
+        let t = token(&node.secret, &from.ip());
+        let query = Query::AnnouncePeer { info_hash: info_hash.clone(), port: from.port(), implied_port: false, token: t };
+        node.answer(vec![0, 0], query, from)
+    }
+
+    #[test]
+    fn lookup_finds_announced_peer() {
+        let info_hash = random_id();
+        let (mut c, _) = start_node(&info_hash, 7003, vec![]);
+        let (mut b, _) = start_node(&info_hash, 7002, vec![addr(&c)]);
+
+        // b looks up the torrent via c, and announces itself there once the lookup is over
+        b.start_lookup(vec![]);
+        poll_all(&mut [&mut b, &mut c], 300);
+        assert!(b.lookup.is_none());
+        assert_eq!(c.peers_for(&info_hash).len(), 1);
+
+        let (mut a, a_rx) = start_node(&info_hash, 7001, vec![addr(&c)]);
+        a.start_lookup(vec![]);
+        poll_all(&mut [&mut a, &mut b, &mut c], 300);
+        let peer = a_rx.try_recv().unwrap();
+        assert_eq!(peer.addr(), SocketAddr::new(addr(&b).ip(), 7002));
+    }
+
+    #[test]
+    fn refresh_fills_routing_table() {
+        let info_hash = random_id();
+        let (mut c, _) = start_node(&info_hash, 7003, vec![]);
+        let (mut b, _) = start_node(&info_hash, 7002, vec![addr(&c)]);
+        let (mut a, _) = start_node(&info_hash, 7001, vec![addr(&c)]);
+
+        // b gets to know c first, then a only learns about b from c's find_node answer, and adds it after pinging it
+        b.refresh();
+        poll_all(&mut [&mut b, &mut c], 200);
+        assert_eq!(c.table.len(), 1);
+
+        a.refresh();
+        poll_all(&mut [&mut a, &mut b, &mut c], 200);
+        assert_eq!(a.table.len(), 2);
+        assert_eq!(b.table.len(), 2);
+        assert_eq!(c.table.len(), 2);
+    }
+
+    #[test]
+    fn announce_store_is_bounded() {
+        let info_hash = random_id();
+        let (mut node, _) = start_node(&info_hash, 7001, vec![]);
+
+        for port in 0..(MAX_PEERS_PER_TORRENT + 10) {
+            announce(&mut node, &info_hash, SocketAddr::new("127.0.0.1".parse().unwrap(), 10000 + port as u16));
+        }
+        let stored = &node.peers[&info_hash];
+        assert_eq!(stored.len(), MAX_PEERS_PER_TORRENT);
+        assert_eq!(stored[0].0.port(), 10010);
+
+        let from = SocketAddr::new("127.0.0.1".parse().unwrap(), 10000);
+        for _ in 1..MAX_TORRENTS {
+            announce(&mut node, &random_id(), from);
+        }
+        assert_eq!(node.peers.len(), MAX_TORRENTS);
+        let message = announce(&mut node, &random_id(), from);
+        assert_eq!(message.body, Body::Error(krpc::ERROR_SERVER, "Too many torrents".to_string()));
+        assert_eq!(node.peers.len(), MAX_TORRENTS);
+    }
+}
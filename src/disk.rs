@@ -0,0 +1,237 @@
+use std::{io, thread};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender};
+use std::thread::JoinHandle;
+
+use ipc::IPC;
+use read_cache::ReadCache;
+use storage::Storage;
+
+// how many threads do disk I/O, and how many queued jobs each one picks up at a time
+const NUM_WORKERS: usize = 4;
+const MAX_JOBS_PER_BATCH: usize = 64;
+
+// once this many jobs are queued, submitting another waits for the workers to catch up
+const MAX_QUEUED_JOBS: usize = NUM_WORKERS * MAX_JOBS_PER_BATCH;
+
+enum Job {
+    // a block_index of None means the whole piece
+    Write { piece_index: u32, block_index: Option<u32>, offset: u64, data: Vec<u8> },
+    Read { piece_index: u32, piece_offset: u32, piece_length: u32, offset: u64, length: u32, reply: Sender<IPC> },
+    Shutdown,
+}
+
+// reads and writes the download's files on a pool of worker threads, so nobody else has to wait for the disk.
+// completed writes are reported on done_tx, and reads are sent back to whoever asked for them. reads go through
+// read_cache, which is filled with the whole piece whenever one of its blocks is read
+pub struct DiskPool {
+    tx: SyncSender<Job>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl DiskPool {
+    pub fn start(storage: Arc<Mutex<Storage>>, read_cache: Arc<Mutex<ReadCache>>, done_tx: Sender<IPC>) -> DiskPool {
+        let (tx, rx) = sync_channel::<Job>(MAX_QUEUED_JOBS);
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut threads = vec![];
+        for _ in 0..NUM_WORKERS {
+            let worker = DiskWorker {
+                storage: storage.clone(),
+                read_cache: read_cache.clone(),
+                rx: rx.clone(),
+                done_tx: done_tx.clone(),
+            };
+            threads.push(thread::spawn(move || worker.run()));
+        }
+
+        DiskPool {
+            tx: tx,
+            threads: threads,
+        }
+    }
+
+    // reports IPC::BlockWritten when done
+    pub fn write(&self, piece_index: u32, block_index: u32, offset: u64, data: Vec<u8>) {
+        self.submit(Job::Write { piece_index: piece_index, block_index: Some(block_index), offset: offset, data: data });
+    }
+
+    // reports IPC::PieceWritten when done
+    pub fn write_piece(&self, piece_index: u32, offset: u64, data: Vec<u8>) {
+        self.submit(Job::Write { piece_index: piece_index, block_index: None, offset: offset, data: data });
+    }
+
+    // read `length` bytes from `piece_offset` into the piece, which starts at `offset` in the files. sends
+    // IPC::BlockRead to reply when done
+    pub fn read(&self, piece_index: u32, piece_offset: u32, piece_length: u32, offset: u64, length: u32, reply: Sender<IPC>) {
+        self.submit(Job::Read { piece_index: piece_index, piece_offset: piece_offset, piece_length: piece_length, offset: offset, length: length, reply: reply });
+    }
+
+    // finish all the jobs that have been submitted, and stop the workers
+    pub fn stop(&mut self) {
+        for _ in 0..self.threads.len() {
+            self.submit(Job::Shutdown);
+        }
+        for thread in self.threads.drain(..) {
+            match thread.join() {
+                Ok(_) => {},
+                Err(e) => println!("Error: {:?}", e)
+            }
+        }
+    }
+
+    fn submit(&self, job: Job) {
+        match self.tx.send(job) {
+            Ok(_) => {},
+            Err(_) => println!("Disk I/O has stopped, dropping job")
+        }
+    }
+}
+
+struct DiskWorker {
+    storage: Arc<Mutex<Storage>>,
+    read_cache: Arc<Mutex<ReadCache>>,
+    rx: Arc<Mutex<Receiver<Job>>>,
+    done_tx: Sender<IPC>,
+}
+
+impl DiskWorker {
+    fn run(&self) {
+        loop {
+            let (jobs, shutdown) = self.next_batch();
+
+            let mut writes = vec![];
+            for job in jobs {
+                match job {
+                    Job::Write { piece_index, block_index, offset, data } => writes.push((offset, piece_index, block_index, data)),
+                    Job::Read { piece_index, piece_offset, piece_length, offset, length, reply } => self.read(piece_index, piece_offset, piece_length, offset, length, reply),
+                    Job::Shutdown => {}
+                }
+            }
+            self.write(writes);
+
+            if shutdown {
+                return;
+            }
+        }
+    }
+
+    // wait for a job, then take whatever else is queued up behind it. a worker stops taking jobs at a Shutdown, so
+    // that every worker gets one
+    fn next_batch(&self) -> (Vec<Job>, bool) {
+        let rx = self.rx.lock().unwrap();
+        let mut jobs = vec![];
+        let mut next = rx.recv().ok();
+        while let Some(job) = next {
+            if let Job::Shutdown = job {
+                return (jobs, true);
+            }
+            jobs.push(job);
+            next = if jobs.len() < MAX_JOBS_PER_BATCH { rx.try_recv().ok() } else { None };
+        }
+        let disconnected = jobs.len() == 0;
+        (jobs, disconnected)
+    }
+
+    // blocks (and pieces) that follow on from each other are written all at once
+    fn write(&self, mut writes: Vec<(u64, u32, Option<u32>, Vec<u8>)>) {
+        writes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut i = 0;
+        while i < writes.len() {
+            let start = writes[i].0;
+            let mut data = vec![];
+            let mut blocks = vec![];
+            while i < writes.len() && writes[i].0 == start + data.len() as u64 {
+                data.extend_from_slice(&writes[i].3);
+                blocks.push((writes[i].1, writes[i].2));
+                i += 1;
+            }
+
+            let result = {
+                let mut storage = self.storage.lock().unwrap();
+                storage.write(start, &data)
+            };
+            let succeeded = match result {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Error writing to disk: {:?}", e);
+                    false
+                }
+            };
+            for (piece_index, block_index) in blocks {
+                // whatever was cached for the piece is out of date now
+                self.read_cache.lock().unwrap().remove(piece_index);
+                match block_index {
+                    Some(block_index) => self.done(IPC::BlockWritten(piece_index, block_index, succeeded)),
+                    None => self.done(IPC::PieceWritten(piece_index, succeeded))
+                }
+            }
+        }
+    }
+
+    fn read(&self, piece_index: u32, piece_offset: u32, piece_length: u32, offset: u64, length: u32, reply: Sender<IPC>) {
+        let data = match self.read_cached(piece_index, piece_offset, piece_length, offset, length) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                println!("Error reading from disk: {:?}", e);
+                None
+            }
+        };
+        match reply.send(IPC::BlockRead(piece_index, piece_offset, data)) {
+            Ok(_) => {},
+            Err(_) => {} // the peer has disconnected, and doesn't need the block anymore
+        }
+    }
+
+    // on a miss, read the whole piece into the cache, since peers tend to ask for the rest of it next. if the piece
+    // is too big for the cache, or another worker is already reading it, only the block is read
+    fn read_cached(&self, piece_index: u32, piece_offset: u32, piece_length: u32, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        if piece_offset as u64 + length as u64 > piece_length as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block runs past the end of the piece"));
+        }
+
+        let read_piece = {
+            let mut read_cache = self.read_cache.lock().unwrap();
+            if read_cache.is_enabled() {
+                if let Some(data) = read_cache.get(piece_index, piece_offset, length) {
+                    return Ok(data);
+                }
+            }
+            read_cache.fits(piece_length as u64) && read_cache.start_loading(piece_index)
+        };
+        if !read_piece {
+            let mut storage = self.storage.lock().unwrap();
+            return storage.read(offset + piece_offset as u64, length as u64);
+        }
+
+        let result = {
+            let mut storage = self.storage.lock().unwrap();
+            storage.read(offset, piece_length as u64)
+        };
+        let mut read_cache = self.read_cache.lock().unwrap();
+        match result {
+            Ok(ref piece) if piece.len() < piece_length as usize => {
+                read_cache.cancel_loading(piece_index);
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "piece hasn't been fully written"))
+            },
+            Ok(piece) => {
+                let start = piece_offset as usize;
+                let data = piece[start..(start + length as usize)].to_vec();
+                read_cache.insert(piece_index, piece);
+                Ok(data)
+            },
+            Err(e) => {
+                read_cache.cancel_loading(piece_index);
+                Err(e)
+            }
+        }
+    }
+
+    fn done(&self, ipc: IPC) {
+        match self.done_tx.send(ipc) {
+            Ok(_) => {},
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+}
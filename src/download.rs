@@ -1,33 +1,123 @@
-use hash::{calculate_sha1, Sha1};
-use std::{convert, io};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
-use std::path::Path;
-use std::sync::mpsc::{Sender, SendError};
+use hash::Sha1;
+use rand;
+use rand::Rng;
+use std::{convert, io, thread};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, SendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use decoder;
+use disk::DiskPool;
+use hasher::HashPool;
 use ipc::IPC;
 use metainfo::Metainfo;
+use rate_limiter::RateLimits;
+use read_cache::ReadCache;
 use request_metadata::RequestMetadata;
+use resume::{PartialPiece, Resume};
+use storage::Storage;
 
 pub const BLOCK_SIZE: u32 = 16384;
 
+// pick pieces at random until we have a few complete ones to trade, then pick the rarest ones
+const RANDOM_FIRST_PIECES: usize = 4;
+
+// ban a peer once this many pieces it sent on its own have failed verification. it's the address that's banned, and
+// counted against, since the peer can pick a new id whenever it reconnects
+const MAX_HASH_FAILURES: u32 = 3;
+
+// how much memory to use for assembling pieces before they're verified and written to disk
+const PIECE_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+// a piece that hasn't had a block in this long gives up its place in the cache, if another piece needs it
+const CACHE_STALL_SECS: u64 = 30;
+
+// how often to check whether the disk I/O has finished, when stopping
+const DISK_IO_POLL_MILLIS: u64 = 50;
+
 pub struct Download {
     pub our_peer_id: String,
+    pub listener_port: u16,
     pub metainfo:    Metainfo,
+    pub rate_limits: Vec<RateLimits>,
     pieces:          Vec<Piece>,
-    file:            File,
+    storage:         Arc<Mutex<Storage>>,
+    disk:            DiskPool,
+    hasher:          HashPool,
+    resume_path:     PathBuf,
     peer_channels:   Vec<Sender<IPC>>,
+    availability:    Vec<u32>,
+    num_complete:    usize,
+    started:         BTreeSet<(u32, u32, u32)>,
+    unstarted:       BTreeSet<(u32, u32, u32)>,
+    free_blocks:     u32,
+    hash_failures:   HashMap<IpAddr, u32>,
+    cached_bytes:    u64,
+    io_in_progress:  u32, // writes and verifications whose results haven't been handled yet
+    banned:          HashSet<IpAddr>,
+    uploaded:        u64,
+    downloaded:      u64,
+}
+
+// peers are told apart by their id as well as their address, so that peers sharing an address (e.g. behind a NAT)
+// aren't blamed for each other's corrupt data
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PeerIdentity {
+    pub ip: IpAddr,
+    pub id: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub uploaded:   u64,
+    pub downloaded: u64,
+    pub left:       u64,
 }
 
 impl Download {
-    pub fn new(our_peer_id: String, metainfo: Metainfo) -> Result<Download, Error> {
+    // rate_limits are all the limits that apply to this download's peers (e.g. global, then per-torrent), and blocks
+    // are uploaded through read_cache. the results of disk I/O are sent to disk_done_tx, and need to be passed back in
+    // with handle_disk_io
+    pub fn new(our_peer_id: String, listener_port: u16, metainfo: Metainfo, rate_limits: Vec<RateLimits>, read_cache: Arc<Mutex<ReadCache>>, disk_done_tx: Sender<IPC>) -> Result<Download, Error> {
         let file_length = metainfo.info.length;
         let piece_length = metainfo.info.piece_length;
         let num_pieces = metainfo.info.num_pieces;
 
-        // create/open file
-        let path = Path::new("downloads").join(&metainfo.info.name);
-        let mut file = try!(OpenOptions::new().create(true).read(true).write(true).open(path));
+        // create/open files
+        let storage = try!(Storage::new(Path::new("downloads"), &metainfo.info));
+
+        // if the files haven't changed since we last stopped, we can trust what we knew about them then. if we didn't
+        // get to stop cleanly, there may be a checkpoint saved while we were downloading. the files will have been
+        // written to since then, but never the pieces that were already complete, so only the rest need checking
+        let resume_path = Path::new("downloads").join(format!("{}.resume", metainfo.info.name));
+        let file_stats = try!(storage.file_stats());
+        let (resume, verify_incomplete) = match Resume::load(&resume_path) {
+            Ok(Some(r)) => {
+                let matches = r.info_hash == metainfo.info_hash && r.pieces.len() == num_pieces as usize;
+                if matches && r.files == file_stats {
+                    println!("Resuming from {}", resume_path.display());
+                    (Some(r), false)
+                } else if matches && r.is_checkpoint {
+                    println!("Resuming from {}, verifying the pieces that weren't complete yet", resume_path.display());
+                    (Some(r), true)
+                } else {
+                    println!("Files have changed since {} was saved, verifying them", resume_path.display());
+                    (None, false)
+                }
+            },
+            Ok(None) => (None, false),
+            Err(e) => {
+                println!("Error loading {}: {:?}", resume_path.display(), e);
+                (None, false)
+            }
+        };
+
+        // there's nothing to verify if nothing has been downloaded yet
+        let is_empty = file_stats.iter().all(|f| f.length == 0);
 
         // create pieces
         let mut pieces = vec![];
@@ -39,63 +129,395 @@ impl Download {
                 (file_length - offset) as u32
             };
             let mut piece = Piece::new(length, offset, metainfo.info.pieces[i as usize].clone());
-            try!(piece.verify(&mut file));
+            if let Some(ref r) = resume {
+                piece.is_complete = r.pieces[i as usize];
+            }
             pieces.push(piece);
         }
 
-        Ok(Download {
+        // the blocks of partially downloaded pieces will be verified along with the rest of the piece
+        if let Some(ref r) = resume {
+            for p in r.partial_pieces.iter() {
+                if let Some(piece) = pieces.get_mut(p.index as usize) {
+                    if !piece.is_complete {
+                        for (block, &has_block) in piece.blocks.iter_mut().zip(p.blocks.iter()) {
+                            block.is_complete = has_block;
+                            block.is_written = has_block;
+                        }
+                    }
+                }
+            }
+        }
+
+        // without resume data, everything on disk needs checking. with it, only the pieces that were fully written but
+        // not yet verified when we stopped do
+        let to_verify: Vec<u32> = match resume {
+            Some(_) if verify_incomplete => (0..num_pieces).filter(|&i| !pieces[i as usize].is_complete).collect(),
+            Some(_) => (0..num_pieces).filter(|&i| !pieces[i as usize].is_complete && pieces[i as usize].has_all_blocks()).collect(),
+            None if !is_empty => (0..num_pieces).collect(),
+            None => vec![]
+        };
+
+        // from now on, the files are only touched by the disk and hashing workers
+        let storage = Arc::new(Mutex::new(storage));
+        verify_pieces(&storage, &mut pieces, to_verify);
+        for piece in pieces.iter_mut() {
+            piece.count_free_blocks();
+        }
+        let num_complete = pieces.iter().filter(|p| p.is_complete).count();
+        let disk = DiskPool::start(storage.clone(), read_cache, disk_done_tx.clone());
+        let hasher = HashPool::start(storage.clone(), disk_done_tx);
+
+        let mut download = Download {
             our_peer_id:   our_peer_id,
+            listener_port: listener_port,
             metainfo:      metainfo,
+            rate_limits:   rate_limits,
             pieces:        pieces,
-            file:          file,
+            storage:       storage,
+            disk:          disk,
+            hasher:        hasher,
+            resume_path:   resume_path,
             peer_channels: vec![],
-        })
+            availability:  vec![0; num_pieces as usize],
+            num_complete:  num_complete,
+            started:       BTreeSet::new(),
+            unstarted:     BTreeSet::new(),
+            free_blocks:   0,
+            hash_failures: HashMap::new(),
+            cached_bytes:  0,
+            io_in_progress: 0,
+            banned:        HashSet::new(),
+            uploaded:      0,
+            downloaded:    0,
+        };
+
+        download.index_all();
+
+        // a complete download won't be written to again, so it can be resumed from now on
+        if download.is_complete() {
+            try!(download.save_resume());
+        }
+
+        Ok(download)
     }
 
-    pub fn register_peer(&mut self, channel: Sender<IPC>) {
+    pub fn register_channel(&mut self, channel: Sender<IPC>) {
         self.peer_channels.push(channel);
     }
 
-    pub fn store(&mut self, piece_index: u32, block_index: u32, data: Vec<u8>) -> Result<(), Error> {
+    // `from` is the peer that sent the block, which is blamed if the piece turns out to be corrupt. pieces are put
+    // together in memory and written to disk once they've been verified, unless there isn't room in the cache, in which
+    // case each block is written to disk in the background as it arrives
+    pub fn store(&mut self, piece_index: u32, block_index: u32, data: Vec<u8>, from: &PeerIdentity) -> Result<(), Error> {
+        if !self.is_valid_block(piece_index, block_index) {
+            return Err(Error::InvalidBlock(piece_index, block_index));
+        }
         {
-            let piece = &mut self.pieces[piece_index as usize];
+            let ref piece = self.pieces[piece_index as usize];
             if piece.is_complete || piece.has_block(block_index) {
                 // if we already have this block, do an early return to avoid re-writing the piece, sending complete messages, etc
                 return Ok(())
             }
-            try!(piece.store(&mut self.file, block_index, data));
+            if !piece.can_download_from(from) {
+                // left over from before the piece failed, so it can't be trusted
+                return Ok(())
+            }
+            if data.len() as u32 != piece.blocks[block_index as usize].length {
+                return Err(Error::WrongBlockLength);
+            }
+        }
+
+        // only count blocks we didn't already have, so endgame duplicates don't inflate what we tell the trackers
+        self.downloaded += data.len() as u64;
+
+        let (is_new, length) = {
+            let ref piece = self.pieces[piece_index as usize];
+            (!piece.blocks.iter().any(|b| b.is_complete), piece.length as u64)
+        };
+        if is_new && self.cached_bytes + length > PIECE_CACHE_SIZE {
+            self.spill_stalled();
+        }
+
+        self.unindex(piece_index);
+        {
+            let piece = &mut self.pieces[piece_index as usize];
+            if is_new && self.cached_bytes + length <= PIECE_CACHE_SIZE {
+                piece.buffer = Some(vec![0; piece.length as usize]);
+                piece.is_cached = true;
+                self.cached_bytes += length;
+            }
+            piece.stored_at = Some(Instant::now());
+
+            piece.change_block(block_index, |block| {
+                block.is_complete = true;
+                block.from = Some(from.clone());
+            });
+
+            let offset = block_index * BLOCK_SIZE;
+            match piece.buffer {
+                Some(ref mut buffer) => buffer[(offset as usize)..(offset as usize + data.len())].copy_from_slice(&data),
+                None => {
+                    self.disk.write(piece_index, block_index, piece.offset + offset as u64, data);
+                    self.io_in_progress += 1;
+                }
+            }
+
+            if piece.buffer.is_some() && piece.has_all_blocks() {
+                let buffer = piece.buffer.take().unwrap();
+                self.hasher.verify_data(piece_index, buffer, piece.hash.clone());
+                self.io_in_progress += 1;
+            }
         }
+        self.index(piece_index);
 
         // notify peers that this block is complete
         self.broadcast(IPC::BlockComplete(piece_index, block_index));
+        Ok(())
+    }
 
-        // notify peers if piece is complete
-        if self.pieces[piece_index as usize].is_complete {
-            self.broadcast(IPC::PieceComplete(piece_index));
+    fn block_written(&mut self, piece_index: u32, block_index: u32, succeeded: bool) {
+        self.io_in_progress -= 1;
+        if !succeeded {
+            // the block has to be downloaded again, so get every peer to re-queue it
+            self.unindex(piece_index);
+            self.pieces[piece_index as usize].change_block(block_index, |block| {
+                block.is_complete = false;
+                block.from = None;
+            });
+            self.index(piece_index);
+            self.broadcast(IPC::PieceFailed(piece_index));
+            return;
         }
 
+        let piece = &mut self.pieces[piece_index as usize];
+        piece.blocks[block_index as usize].is_written = true;
+        if piece.blocks.iter().all(|b| b.is_written) {
+            self.hasher.verify(piece_index, piece.offset, piece.length as u64, piece.hash.clone());
+            self.io_in_progress += 1;
+        }
+    }
+
+    // data is the piece itself, if it was verified from memory
+    fn piece_verified(&mut self, piece_index: u32, valid: bool, data: Option<Vec<u8>>) {
+        self.io_in_progress -= 1;
+        if !valid {
+            // the whole piece has to be downloaded again, by anyone who has it
+            println!("Piece {} failed verification", piece_index);
+            self.uncache(piece_index);
+            self.unindex(piece_index);
+            let mut contributors = self.pieces[piece_index as usize].reset_blocks();
+            {
+                // a piece that came from a single peer is that peer's fault. otherwise, any of them could have sent the
+                // bad block, so the piece is downloaded again from just one of them, which will either fix it or show
+                // who's to blame
+                let piece = &mut self.pieces[piece_index as usize];
+                piece.claimed_by = None;
+                piece.is_suspect = contributors.len() > 1;
+                if piece.is_suspect {
+                    println!("Piece {} came from {} peers, so getting it again from just one", piece_index, contributors.len());
+                }
+            }
+            self.index(piece_index);
+            self.broadcast(IPC::PieceFailed(piece_index));
+            if contributors.len() == 1 {
+                self.hash_failed(contributors.remove(0));
+            }
+            return;
+        }
+
+        match data {
+            Some(data) => {
+                let offset = self.pieces[piece_index as usize].offset;
+                self.disk.write_piece(piece_index, offset, data);
+                self.io_in_progress += 1;
+            },
+            None => self.piece_complete(piece_index)
+        }
+    }
+
+    fn piece_written(&mut self, piece_index: u32, succeeded: bool) {
+        self.io_in_progress -= 1;
+        self.uncache(piece_index);
+        if !succeeded {
+            self.unindex(piece_index);
+            self.pieces[piece_index as usize].reset_blocks();
+            self.index(piece_index);
+            self.broadcast(IPC::PieceFailed(piece_index));
+            return;
+        }
+
+        for block in self.pieces[piece_index as usize].blocks.iter_mut() {
+            block.is_written = true;
+        }
+        self.piece_complete(piece_index);
+    }
+
+    // a piece is only complete once it's on disk, so that it can be uploaded
+    fn piece_complete(&mut self, piece_index: u32) {
+        // notify peers that the piece is complete
+        self.unindex(piece_index);
+        {
+            let piece = &mut self.pieces[piece_index as usize];
+            piece.is_complete = true;
+            piece.is_suspect = false;
+            piece.claimed_by = None;
+        }
+        self.num_complete += 1;
+        if self.num_complete == RANDOM_FIRST_PIECES {
+            // we have enough to trade now, so rarity decides the order from here on
+            self.index_all();
+        }
+        self.broadcast(IPC::PieceComplete(piece_index));
+
         // notify peers if download is complete
         if self.is_complete() {
             println!("Download complete");
             self.broadcast(IPC::DownloadComplete);
         }
+    }
 
-        Ok(())
+    // write what we have of a piece that's being put together in memory to disk, and free up its place in the cache.
+    // the rest of its blocks are written to disk as they arrive
+    fn spill(&mut self, piece_index: u32) {
+        let writes: Vec<(u32, u64, Vec<u8>)> = {
+            let piece = &mut self.pieces[piece_index as usize];
+            let buffer = match piece.buffer.take() {
+                Some(buffer) => buffer,
+                None => return
+            };
+            piece.blocks.iter().filter(|b| b.is_complete).map(|b| {
+                let start = (b.index * BLOCK_SIZE) as usize;
+                (b.index, piece.offset + start as u64, buffer[start..(start + b.length as usize)].to_vec())
+            }).collect()
+        };
+        self.uncache(piece_index);
+        for (block_index, offset, data) in writes.into_iter() {
+            self.disk.write(piece_index, block_index, offset, data);
+            self.io_in_progress += 1;
+        }
     }
 
-    pub fn retrive_data(&mut self, request: &RequestMetadata) -> Result<Vec<u8>, Error> {
+    // make room in the cache by spilling the pieces that have stopped getting blocks (e.g. because the peers that had
+    // them went away)
+    fn spill_stalled(&mut self) {
+        let stall = Duration::from_secs(CACHE_STALL_SECS);
+        let stalled: Vec<u32> = (0..self.pieces.len() as u32).filter(|&i| {
+            let ref piece = self.pieces[i as usize];
+            piece.buffer.is_some() && piece.stored_at.map_or(true, |t| t.elapsed() >= stall)
+        }).collect();
+        for piece_index in stalled.into_iter() {
+            self.spill(piece_index);
+        }
+    }
+
+    fn uncache(&mut self, piece_index: u32) {
+        let piece = &mut self.pieces[piece_index as usize];
+        if piece.is_cached {
+            piece.is_cached = false;
+            self.cached_bytes -= piece.length as u64;
+        }
+    }
+
+    // save what we know about the download, so we don't have to verify the files on the next startup. nothing must be
+    // written to the files after this, or the resume data will be thrown away
+    pub fn save_resume(&self) -> Result<(), Error> {
+        self.snapshot(false).save()
+    }
+
+    // what we know so far, to be saved in case we don't get to stop cleanly. saving it doesn't need the download, so it
+    // can be done without holding up the peers
+    pub fn checkpoint(&self) -> ResumeSnapshot {
+        self.snapshot(true)
+    }
+
+    fn snapshot(&self, is_checkpoint: bool) -> ResumeSnapshot {
+        let mut partial_pieces = vec![];
+        for (i, piece) in self.pieces.iter().enumerate() {
+            if !piece.is_complete && piece.blocks.iter().any(|b| b.is_written) {
+                partial_pieces.push(PartialPiece {
+                    index: i as u32,
+                    blocks: piece.blocks.iter().map(|b| b.is_written).collect(),
+                });
+            }
+        }
+
+        ResumeSnapshot {
+            resume: Resume {
+                info_hash: self.metainfo.info_hash.clone(),
+                pieces: self.have_pieces(),
+                partial_pieces: partial_pieces,
+                files: vec![], // filled in when it's saved
+                is_checkpoint: is_checkpoint,
+            },
+            storage: self.storage.clone(),
+            path: self.resume_path.clone(),
+        }
+    }
+
+    // disconnect from every peer
+    pub fn shutdown(&mut self) {
+        self.broadcast(IPC::Shutdown);
+    }
+
+    pub fn is_banned(&self, peer: &PeerIdentity) -> bool {
+        self.banned.contains(&peer.ip)
+    }
+
+    fn hash_failed(&mut self, peer: PeerIdentity) {
+        let failures = {
+            let count = self.hash_failures.entry(peer.ip).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if failures >= MAX_HASH_FAILURES && self.banned.insert(peer.ip) {
+            println!("Banning {} for sending corrupt data", peer.ip);
+            self.broadcast(IPC::PeerBanned(peer.ip));
+        }
+    }
+
+    // give up the pieces the peer was getting for us on its own (e.g. because it went away, or stopped answering), so
+    // another peer can have a go. whatever it already sent of them is thrown away, to keep each piece from a single peer
+    pub fn release_claims(&mut self, peer: &PeerIdentity) {
+        let claimed: Vec<u32> = (0..self.pieces.len() as u32).filter(|&i| {
+            let ref piece = self.pieces[i as usize];
+            piece.claimed_by.as_ref() == Some(peer) && !piece.has_all_blocks()
+        }).collect();
+
+        for piece_index in claimed.into_iter() {
+            self.uncache(piece_index);
+            self.unindex(piece_index);
+            {
+                let piece = &mut self.pieces[piece_index as usize];
+                piece.reset_blocks();
+                piece.claimed_by = None;
+            }
+            self.index(piece_index);
+            self.broadcast(IPC::PieceFailed(piece_index));
+        }
+    }
+
+    // the block is read in the background, and sent to reply in an IPC::BlockRead
+    pub fn read_block(&mut self, request: &RequestMetadata, reply: Sender<IPC>) -> Result<(), Error> {
+        if !self.is_valid_block(request.piece_index, request.block_index) {
+            return Err(Error::InvalidBlock(request.piece_index, request.block_index));
+        }
         let ref piece = self.pieces[request.piece_index as usize];
         if piece.is_complete {
-            let offset = piece.offset + request.offset as u64;
-            let file = &mut self.file;
-            try!(file.seek(io::SeekFrom::Start(offset)));
-            let mut buf = vec![];
-            try!(file.take(request.block_length as u64).read_to_end(&mut buf));
-            Ok(buf)
+            self.disk.read(request.piece_index, request.offset, piece.length, piece.offset, request.block_length, reply);
+            self.uploaded += request.block_length as u64;
+            Ok(())
         } else {
             Err(Error::MissingPieceData)
         }
+    }
 
+    // whether a block a peer has sent or asked for is actually part of the torrent
+    fn is_valid_block(&self, piece_index: u32, block_index: u32) -> bool {
+        match self.pieces.get(piece_index as usize) {
+            Some(piece) => (block_index as usize) < piece.blocks.len(),
+            None => false
+        }
     }
 
     pub fn have_pieces(&self) -> Vec<bool> {
@@ -111,7 +533,148 @@ impl Download {
         }
     }
 
-    fn is_complete(&self) -> bool {
+    // keep count of how many connected peers have each piece
+    pub fn peer_has_piece(&mut self, piece_index: u32) {
+        self.unindex(piece_index);
+        self.availability[piece_index as usize] += 1;
+        self.index(piece_index);
+    }
+
+    pub fn peer_has_pieces(&mut self, has_pieces: &[bool]) {
+        for (piece_index, _) in has_pieces.iter().take(self.pieces.len()).enumerate().filter(|&(_, &has)| has) {
+            self.peer_has_piece(piece_index as u32);
+        }
+    }
+
+    pub fn peer_lost_pieces(&mut self, has_pieces: &[bool]) {
+        for (piece_index, _) in has_pieces.iter().take(self.pieces.len()).enumerate().filter(|&(_, &has)| has) {
+            if self.availability[piece_index] > 0 {
+                self.unindex(piece_index as u32);
+                self.availability[piece_index] -= 1;
+                self.index(piece_index as u32);
+            }
+        }
+    }
+
+    // keep count of how many peers each block is currently requested from. the first peer to be asked for a block of
+    // a suspect piece gets the rest of it too
+    pub fn block_requested(&mut self, piece_index: u32, block_index: u32, by: &PeerIdentity) {
+        self.unindex(piece_index);
+        {
+            let piece = &mut self.pieces[piece_index as usize];
+            piece.change_block(block_index, |block| block.requests += 1);
+            if piece.is_suspect && piece.claimed_by.is_none() {
+                piece.claimed_by = Some(by.clone());
+            }
+        }
+        self.index(piece_index);
+    }
+
+    pub fn block_unrequested(&mut self, piece_index: u32, block_index: u32) {
+        self.unindex(piece_index);
+        self.pieces[piece_index as usize].change_block(block_index, |block| {
+            if block.requests > 0 {
+                block.requests -= 1;
+            }
+        });
+        self.index(piece_index);
+    }
+
+    // choose which of the given (piece index, block index) pairs to request next. blocks nobody else has requested
+    // come first, from pieces that are already under way and then from the rarest pieces. blocks that are already
+    // requested from another peer are only picked in endgame mode
+    pub fn pick_block<V>(&self, candidates: &HashMap<(u32, u32), V>, peer: &PeerIdentity) -> Option<(u32, u32)> {
+        for &(_, _, piece_index) in self.started.iter() {
+            let ref piece = self.pieces[piece_index as usize];
+            if !piece.can_download_from(peer) {
+                continue;
+            }
+            if let Some(block) = piece.blocks.iter().find(|b| b.is_free() && candidates.contains_key(&(piece_index, b.index))) {
+                return Some((piece_index, block.index));
+            }
+        }
+
+        // a peer that has a piece has all of its blocks queued, so there's no need to look past the first one
+        for &(_, _, piece_index) in self.unstarted.iter() {
+            if candidates.contains_key(&(piece_index, 0)) && self.pieces[piece_index as usize].can_download_from(peer) {
+                return Some((piece_index, 0));
+            }
+        }
+
+        if !self.is_endgame() {
+            return None;
+        }
+
+        // other peers may have completed some of the candidates since they were queued
+        candidates.keys()
+            .filter(|&&(piece_index, block_index)| {
+                let ref piece = self.pieces[piece_index as usize];
+                !piece.has_block(block_index) && piece.can_download_from(peer)
+            })
+            .min_by_key(|&&(piece_index, block_index)| {
+                let requests = self.pieces[piece_index as usize].blocks[block_index as usize].requests;
+                (requests, self.availability[piece_index as usize], block_index)
+            })
+            .cloned()
+    }
+
+    // where a piece comes in the order blocks are picked in. pieces are picked at random until we have a few complete
+    // ones to trade, then the rarest ones come first. ties are broken randomly, but the same way each time, so the
+    // blocks of each piece stay together
+    fn pick_order(&self, piece_index: u32) -> (u32, u32, u32) {
+        let availability = if self.num_complete < RANDOM_FIRST_PIECES { 0 } else { self.availability[piece_index as usize] };
+        (availability, self.pieces[piece_index as usize].tie_breaker, piece_index)
+    }
+
+    // pieces with blocks nobody has requested yet are kept in pick order, so pick_block doesn't have to sort through
+    // every block, along with a count of those blocks. anything that changes a piece's place or its free blocks has to
+    // unindex it first, and index it again afterwards
+    fn index(&mut self, piece_index: u32) {
+        let key = self.pick_order(piece_index);
+        let ref piece = self.pieces[piece_index as usize];
+        if piece.is_complete || piece.free_blocks == 0 {
+            return;
+        }
+        self.free_blocks += piece.free_blocks;
+        if piece.is_started() {
+            self.started.insert(key);
+        } else {
+            self.unstarted.insert(key);
+        }
+    }
+
+    fn unindex(&mut self, piece_index: u32) {
+        let key = self.pick_order(piece_index);
+        if self.started.remove(&key) || self.unstarted.remove(&key) {
+            self.free_blocks -= self.pieces[piece_index as usize].free_blocks;
+        }
+    }
+
+    fn index_all(&mut self) {
+        self.started.clear();
+        self.unstarted.clear();
+        self.free_blocks = 0;
+        for piece_index in 0..self.pieces.len() {
+            self.index(piece_index as u32);
+        }
+    }
+
+    // once every remaining block has been requested, the last few are requested from every peer that has them, so
+    // the download isn't left waiting on the slowest peer
+    fn is_endgame(&self) -> bool {
+        self.free_blocks == 0
+    }
+
+    pub fn stats(&self) -> Stats {
+        let left = self.pieces.iter().filter(|p| !p.is_complete).map(|p| p.length as u64).sum();
+        Stats {
+            uploaded:   self.uploaded,
+            downloaded: self.downloaded,
+            left:       left,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
         for piece in self.pieces.iter() {
             if !piece.is_complete {
                 return false
@@ -130,12 +693,109 @@ impl Download {
     }
 }
 
+// check the pieces' hashes in parallel, and wait for the results
+fn verify_pieces(storage: &Arc<Mutex<Storage>>, pieces: &mut [Piece], indexes: Vec<u32>) {
+    if indexes.len() == 0 {
+        return;
+    }
+    println!("Verifying {} pieces", indexes.len());
+
+    let (tx, rx) = channel::<IPC>();
+    let mut hasher = HashPool::start(storage.clone(), tx);
+    for &i in indexes.iter() {
+        let ref piece = pieces[i as usize];
+        hasher.verify(i, piece.offset, piece.length as u64, piece.hash.clone());
+    }
+    for _ in 0..indexes.len() {
+        match rx.recv() {
+            Ok(IPC::PieceVerified(piece_index, valid, _)) => {
+                let piece = &mut pieces[piece_index as usize];
+                if valid {
+                    piece.is_complete = true;
+                } else {
+                    piece.reset_blocks();
+                }
+            },
+            Ok(_) => {},
+            Err(e) => {
+                println!("Error: {:?}", e);
+                break;
+            }
+        }
+    }
+    hasher.stop();
+}
+
+// wait for everything that has been stored to be written to disk, and checked, then stop the disk and hashing workers.
+// the download is only locked to check on it, so that handle_disk_io can keep applying the results in the meantime
+// (which may start more I/O, e.g. writing a piece once it has been verified). pieces that were still being put together
+// in memory are written out as they are, to be finished next time
+pub fn finish_disk_io(download_mutex: &Arc<Mutex<Download>>) {
+    {
+        let mut download = download_mutex.lock().unwrap();
+        for piece_index in 0..download.pieces.len() as u32 {
+            download.spill(piece_index);
+        }
+    }
+
+    loop {
+        {
+            let download = download_mutex.lock().unwrap();
+            if download.io_in_progress == 0 {
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(DISK_IO_POLL_MILLIS));
+    }
+
+    let mut download = download_mutex.lock().unwrap();
+    download.disk.stop();
+    download.hasher.stop();
+}
+
+// apply the results of disk I/O to the download, as the disk workers finish them. this stops once the workers do
+pub fn handle_disk_io(download_mutex: Arc<Mutex<Download>>, rx: Receiver<IPC>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for ipc in rx.iter() {
+            let mut download = download_mutex.lock().unwrap();
+            match ipc {
+                IPC::BlockWritten(piece_index, block_index, succeeded) => download.block_written(piece_index, block_index, succeeded),
+                IPC::PieceVerified(piece_index, valid, data) => download.piece_verified(piece_index, valid, data),
+                IPC::PieceWritten(piece_index, succeeded) => download.piece_written(piece_index, succeeded),
+                _ => {}
+            }
+        }
+    })
+}
+
+// resume data, waiting for the files' sizes and modification times before it can be saved
+pub struct ResumeSnapshot {
+    resume: Resume,
+    storage: Arc<Mutex<Storage>>,
+    path: PathBuf,
+}
+
+impl ResumeSnapshot {
+    pub fn save(mut self) -> Result<(), Error> {
+        self.resume.files = try!(self.storage.lock().unwrap().file_stats());
+        try!(self.resume.save(&self.path));
+        Ok(())
+    }
+}
+
 struct Piece {
     length:      u32,
     offset:      u64,
     hash:        Sha1,
     blocks:      Vec<Block>,
     is_complete: bool,
+    buffer:      Option<Vec<u8>>, // the piece being put together in memory
+    is_cached:   bool,
+    free_blocks: u32, // blocks that are neither complete nor requested
+    tie_breaker: u32,
+    is_suspect:  bool, // failed verification with blocks from several peers
+    claimed_by:  Option<PeerIdentity>, // the one peer a suspect piece is being downloaded from
+    stored_at:   Option<Instant>, // when the last block arrived
 }
 
 impl Piece {
@@ -158,41 +818,42 @@ impl Piece {
             hash:        hash,
             blocks:      blocks,
             is_complete: false,
+            buffer:      None,
+            is_cached:   false,
+            free_blocks: num_blocks,
+            tie_breaker: rand::thread_rng().gen(),
+            is_suspect:  false,
+            claimed_by:  None,
+            stored_at:   None,
         }
     }
 
-    fn store(&mut self, file: &mut File, block_index: u32, data: Vec<u8>) -> Result<(), Error> {
-        {
-            // store data in the appropriate point in the file
-            let offset = self.offset + (block_index * BLOCK_SIZE) as u64;
-            try!(file.seek(io::SeekFrom::Start(offset)));
-            try!(file.write_all(&data));
-            self.blocks[block_index as usize].is_complete = true;
-        }
-
-        if self.has_all_blocks() {
-            let valid = try!(self.verify(file));
-            if !valid {
-                self.reset_blocks();
-            }
-        }
+    fn has_block(&self, block_index: u32) -> bool {
+        self.blocks[block_index as usize].is_complete
+    }
 
-        Ok(())
+    fn can_download_from(&self, peer: &PeerIdentity) -> bool {
+        !self.is_suspect || self.claimed_by.as_ref().map_or(true, |p| p == peer)
     }
 
-    fn verify(&mut self, file: &mut File) -> Result<bool, Error> {
-        // read in the part of the file corresponding to the piece
-        try!(file.seek(io::SeekFrom::Start(self.offset)));
-        let mut data = vec![];
-        try!(file.take(self.length as u64).read_to_end(&mut data));
+    fn is_started(&self) -> bool {
+        self.free_blocks < self.blocks.len() as u32
+    }
 
-        // calculate the hash, verify it, and update is_complete
-        self.is_complete = self.hash == calculate_sha1(&data);
-        Ok(self.is_complete)
+    // all changes to blocks go through here (or reset_blocks), to keep count of the free ones
+    fn change_block<F: FnOnce(&mut Block)>(&mut self, block_index: u32, change: F) {
+        let block = &mut self.blocks[block_index as usize];
+        let was_free = block.is_free();
+        change(block);
+        match (was_free, block.is_free()) {
+            (true, false) => self.free_blocks -= 1,
+            (false, true) => self.free_blocks += 1,
+            _ => {}
+        }
     }
 
-    fn has_block(&self, block_index: u32) -> bool {
-        self.blocks[block_index as usize].is_complete
+    fn count_free_blocks(&mut self) {
+        self.free_blocks = self.blocks.iter().filter(|b| b.is_free()).count() as u32;
     }
 
     fn has_all_blocks(&self) -> bool {
@@ -204,10 +865,21 @@ impl Piece {
         true
     }
 
-    fn reset_blocks(&mut self) {
+    // returns the peers the blocks came from
+    fn reset_blocks(&mut self) -> Vec<PeerIdentity> {
+        self.buffer = None;
+        let mut contributors = vec![];
         for block in self.blocks.iter_mut() {
             block.is_complete = false;
+            block.is_written = false;
+            if let Some(peer) = block.from.take() {
+                if !contributors.contains(&peer) {
+                    contributors.push(peer);
+                }
+            }
         }
+        self.count_free_blocks();
+        contributors
     }
 }
 
@@ -215,6 +887,9 @@ struct Block {
     index:       u32,
     length:      u32,
     is_complete: bool,
+    is_written:  bool,
+    requests:    u32,
+    from:        Option<PeerIdentity>,
 }
 
 impl Block {
@@ -223,18 +898,180 @@ impl Block {
             index:       index,
             length:      length,
             is_complete: false,
+            is_written:  false,
+            requests:    0,
+            from:        None,
         }
     }
+
+    fn is_free(&self) -> bool {
+        !self.is_complete && self.requests == 0
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     MissingPieceData,
+    WrongBlockLength,
+    InvalidBlock(u32, u32),
+    DecoderError(decoder::Error),
     IoError(io::Error),
 }
 
+impl convert::From<decoder::Error> for Error {
+    fn from(err: decoder::Error) -> Error {
+        Error::DecoderError(err)
+    }
+}
+
 impl convert::From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IoError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::collections::HashMap;
+
+    use metainfo::{FileInfo, Info};
+
+    const NUM_PIECES: u32 = RANDOM_FIRST_PIECES as u32 + 4;
+
+    // a download of two block pieces, with enough of them complete that the rarest pieces are picked first
+    fn download(name: &str) -> (Download, Receiver<IPC>, PathBuf) {
+        let directory = env::temp_dir().join(format!("rusty_torrent_test_{}_{}", name, process::id()));
+        let piece_length = 2 * BLOCK_SIZE;
+        let info = Info {
+            piece_length: piece_length,
+            pieces: vec![vec![0; 20]; NUM_PIECES as usize],
+            num_pieces: NUM_PIECES,
+            name: "test".to_string(),
+            length: NUM_PIECES as u64 * piece_length as u64,
+            files: vec![FileInfo { length: NUM_PIECES as u64 * piece_length as u64, path: PathBuf::from("test") }],
+        };
+        let storage = Arc::new(Mutex::new(Storage::new(&directory, &info).unwrap()));
+        let (disk_done_tx, disk_done_rx) = channel();
+        let pieces = (0..NUM_PIECES).map(|i| {
+            let mut piece = Piece::new(piece_length, i as u64 * piece_length as u64, vec![0; 20]);
+            piece.is_complete = (i as usize) < RANDOM_FIRST_PIECES;
+            piece
+        }).collect();
+
+        let mut download = Download {
+            our_peer_id:   "-RC0001-000000000000".to_string(),
+            listener_port: 6881,
+            metainfo:      Metainfo { announce: String::new(), announce_list: vec![], info: info, info_hash: vec![0; 20], created_by: String::new() },
+            rate_limits:   vec![],
+            pieces:        pieces,
+            storage:       storage.clone(),
+            disk:          DiskPool::start(storage.clone(), Arc::new(Mutex::new(ReadCache::new(0))), disk_done_tx.clone()),
+            hasher:        HashPool::start(storage, disk_done_tx),
+            resume_path:   directory.join("test.resume"),
+            peer_channels: vec![],
+            availability:  vec![0; NUM_PIECES as usize],
+            num_complete:  RANDOM_FIRST_PIECES,
+            started:       BTreeSet::new(),
+            unstarted:     BTreeSet::new(),
+            free_blocks:   0,
+            hash_failures: HashMap::new(),
+            cached_bytes:  0,
+            io_in_progress: 0,
+            banned:        HashSet::new(),
+            uploaded:      0,
+            downloaded:    0,
+        };
+        download.index_all();
+        (download, disk_done_rx, directory)
+    }
+
+    fn finish(mut download: Download, directory: PathBuf) {
+        download.disk.stop();
+        download.hasher.stop();
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    fn peer() -> PeerIdentity {
+        PeerIdentity { ip: "127.0.0.1".parse().unwrap(), id: vec![1; 20] }
+    }
+
+    fn blocks_of(pieces: &[u32]) -> HashMap<(u32, u32), ()> {
+        pieces.iter().flat_map(|&p| vec![((p, 0), ()), ((p, 1), ())]).collect()
+    }
+
+    #[test]
+    fn picks_rarest_pieces_first() {
+        let (mut download, _, directory) = download("rarest");
+        let first = RANDOM_FIRST_PIECES as u32;
+        for &(piece_index, peers) in [(first, 3), (first + 1, 1), (first + 2, 2), (first + 3, 4)].iter() {
+            for _ in 0..peers {
+                download.peer_has_piece(piece_index);
+            }
+        }
+
+        let all = blocks_of(&[first, first + 1, first + 2, first + 3]);
+        assert_eq!(download.pick_block(&all, &peer()), Some((first + 1, 0)));
+        let not_rarest = blocks_of(&[first, first + 2, first + 3]);
+        assert_eq!(download.pick_block(&not_rarest, &peer()), Some((first + 2, 0)));
+
+        // a peer that has the rarest piece again moves it back
+        download.peer_has_piece(first + 1);
+        download.peer_has_piece(first + 1);
+        assert_eq!(download.pick_block(&all, &peer()), Some((first + 2, 0)));
+
+        finish(download, directory);
+    }
+
+    #[test]
+    fn picks_started_pieces_first() {
+        let (mut download, _, directory) = download("started");
+        let first = RANDOM_FIRST_PIECES as u32;
+        download.peer_has_piece(first + 3);
+        download.peer_has_piece(first + 3);
+
+        // the most common piece, but it's under way
+        download.block_requested(first + 3, 0, &peer());
+        let all = blocks_of(&[first, first + 1, first + 2, first + 3]);
+        assert_eq!(download.pick_block(&all, &peer()), Some((first + 3, 1)));
+
+        finish(download, directory);
+    }
+
+    #[test]
+    fn endgame_starts_when_every_block_is_requested() {
+        let (mut download, disk_done_rx, directory) = download("endgame");
+        let first = RANDOM_FIRST_PIECES as u32;
+        assert_eq!(download.free_blocks, 8);
+
+        let blocks: Vec<(u32, u32)> = (first..NUM_PIECES).flat_map(|p| vec![(p, 0), (p, 1)]).collect();
+        for (i, &(piece_index, block_index)) in blocks.iter().enumerate() {
+            assert!(!download.is_endgame());
+            download.block_requested(piece_index, block_index, &peer());
+            assert_eq!(download.free_blocks, (blocks.len() - i - 1) as u32);
+        }
+        assert!(download.is_endgame());
+
+        // a block that arrives stays taken, and a request that times out frees its block again
+        download.block_unrequested(first, 0);
+        download.store(first, 0, vec![0; BLOCK_SIZE as usize], &peer()).unwrap();
+        assert!(download.is_endgame());
+        download.block_unrequested(first + 1, 0);
+        assert_eq!(download.free_blocks, 1);
+        download.block_requested(first + 1, 0, &peer());
+
+        // a piece that fails verification is downloaded again
+        download.block_unrequested(first, 1);
+        download.store(first, 1, vec![0; BLOCK_SIZE as usize], &peer()).unwrap();
+        match disk_done_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            IPC::PieceVerified(piece_index, valid, data) => download.piece_verified(piece_index, valid, data),
+            _ => panic!("expected the piece to be verified")
+        }
+        assert_eq!(download.free_blocks, 2);
+        assert!(!download.is_endgame());
+        assert_eq!(download.hash_failures.get(&peer().ip), Some(&1));
+
+        finish(download, directory);
+    }
+}
@@ -0,0 +1,110 @@
+use bencode;
+use bencode::{Bencode, FromBencode, ToBencode};
+use bencode::util::ByteString;
+use std::collections::{BTreeMap, HashMap};
+
+use decoder;
+
+// the extension protocol is advertised with bit 20 (counting from the right) of the handshake's reserved bytes (BEP 10)
+pub const RESERVED_BYTE: usize = 5;
+pub const RESERVED_BIT: u8 = 0x10;
+
+// extended message id 0 is always the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+// the ids that peers should use when sending us extension messages
+pub const UT_METADATA_ID: u8 = 1;
+pub const UT_PEX_ID: u8 = 2;
+
+pub const CLIENT_VERSION: &'static str = "RustyTorrent 0.0.1";
+
+#[derive(PartialEq, Debug)]
+pub struct ExtendedHandshake {
+    pub extensions: HashMap<String, u8>,
+    pub client: Option<String>,
+    pub port: Option<u16>,
+    pub reqq: Option<u32>,
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn new(listener_port: u16, reqq: Option<u32>, metadata_size: Option<u32>) -> ExtendedHandshake {
+        let mut extensions = HashMap::new();
+        extensions.insert("ut_metadata".to_string(), UT_METADATA_ID);
+        extensions.insert("ut_pex".to_string(), UT_PEX_ID);
+
+        ExtendedHandshake {
+            extensions: extensions,
+            client: Some(CLIENT_VERSION.to_string()),
+            port: Some(listener_port),
+            reqq: reqq,
+            metadata_size: metadata_size,
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<ExtendedHandshake, decoder::Error> {
+        let bencode = try!(decoder::parse_untrusted(bytes));
+        FromBencode::from_bencode(&bencode)
+    }
+
+    // the id the peer wants us to use when sending them the given extension message, if they support it
+    pub fn id_for(&self, extension: &str) -> Option<u8> {
+        self.extensions.get(extension).cloned()
+    }
+}
+
+impl FromBencode for ExtendedHandshake {
+    type Err = decoder::Error;
+
+    fn from_bencode(bencode: &bencode::Bencode) -> Result<ExtendedHandshake, decoder::Error> {
+        match bencode {
+            &Bencode::Dict(ref m) => {
+                // an id of 0 means the peer has disabled that extension
+                let mut extensions = HashMap::new();
+                if let Some(&Bencode::Dict(ref e)) = m.get(&ByteString::from_str("m")) {
+                    for (name, id) in e.iter() {
+                        let id: u8 = try!(FromBencode::from_bencode(id));
+                        if id != 0 {
+                            extensions.insert(String::from_utf8_lossy(name.as_slice()).into_owned(), id);
+                        }
+                    }
+                }
+
+                let handshake = ExtendedHandshake {
+                    extensions: extensions,
+                    client: get_optional_field!(m, "v"),
+                    port: get_optional_field!(m, "p"),
+                    reqq: get_optional_field!(m, "reqq"),
+                    metadata_size: get_optional_field!(m, "metadata_size"),
+                };
+                Ok(handshake)
+            }
+            _ => Err(decoder::Error::NotADict)
+        }
+    }
+}
+
+impl ToBencode for ExtendedHandshake {
+    fn to_bencode(&self) -> Bencode {
+        let mut extensions = BTreeMap::new();
+        for (name, id) in self.extensions.iter() {
+            extensions.insert(ByteString::from_str(name), id.to_bencode());
+        }
+
+        let mut m = BTreeMap::new();
+        m.insert(ByteString::from_str("m"), Bencode::Dict(extensions));
+        if let Some(ref client) = self.client {
+            m.insert(ByteString::from_str("v"), client.to_bencode());
+        }
+        if let Some(port) = self.port {
+            m.insert(ByteString::from_str("p"), port.to_bencode());
+        }
+        if let Some(reqq) = self.reqq {
+            m.insert(ByteString::from_str("reqq"), reqq.to_bencode());
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            m.insert(ByteString::from_str("metadata_size"), metadata_size.to_bencode());
+        }
+        Bencode::Dict(m)
+    }
+}
@@ -0,0 +1,128 @@
+use num_cpus;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender};
+use std::thread::JoinHandle;
+
+use hash::{calculate_sha1, Sha1};
+use ipc::IPC;
+use storage::Storage;
+
+// once this many pieces per worker are queued, submitting another waits for the workers to catch up
+const MAX_QUEUED_JOBS_PER_WORKER: usize = 4;
+
+enum Job {
+    Verify { piece_index: u32, offset: u64, length: u64, hash: Sha1 },
+    VerifyData { piece_index: u32, data: Vec<u8>, hash: Sha1 },
+    Shutdown,
+}
+
+// checks the hashes of pieces on a thread for each core, reporting IPC::PieceVerified on done_tx as each one finishes
+pub struct HashPool {
+    tx: SyncSender<Job>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl HashPool {
+    pub fn start(storage: Arc<Mutex<Storage>>, done_tx: Sender<IPC>) -> HashPool {
+        let num_workers = num_cpus::get();
+        let (tx, rx) = sync_channel::<Job>(num_workers * MAX_QUEUED_JOBS_PER_WORKER);
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut threads = vec![];
+        for _ in 0..num_workers {
+            let worker = HashWorker {
+                storage: storage.clone(),
+                rx: rx.clone(),
+                done_tx: done_tx.clone(),
+            };
+            threads.push(thread::spawn(move || worker.run()));
+        }
+
+        HashPool {
+            tx: tx,
+            threads: threads,
+        }
+    }
+
+    // check a piece that has been written to disk
+    pub fn verify(&self, piece_index: u32, offset: u64, length: u64, hash: Sha1) {
+        self.submit(piece_index, Job::Verify { piece_index: piece_index, offset: offset, length: length, hash: hash });
+    }
+
+    // check a piece that is still in memory. if it's valid, the data is passed back along with the result
+    pub fn verify_data(&self, piece_index: u32, data: Vec<u8>, hash: Sha1) {
+        self.submit(piece_index, Job::VerifyData { piece_index: piece_index, data: data, hash: hash });
+    }
+
+    // finish checking the pieces that have been submitted, and stop the workers
+    pub fn stop(&mut self) {
+        for _ in 0..self.threads.len() {
+            match self.tx.send(Job::Shutdown) {
+                Ok(_) => {},
+                Err(e) => println!("Error: {:?}", e)
+            }
+        }
+        for thread in self.threads.drain(..) {
+            match thread.join() {
+                Ok(_) => {},
+                Err(e) => println!("Error: {:?}", e)
+            }
+        }
+    }
+
+    fn submit(&self, piece_index: u32, job: Job) {
+        match self.tx.send(job) {
+            Ok(_) => {},
+            Err(_) => println!("Hashing has stopped, not verifying piece {}", piece_index)
+        }
+    }
+}
+
+struct HashWorker {
+    storage: Arc<Mutex<Storage>>,
+    rx: Arc<Mutex<Receiver<Job>>>,
+    done_tx: Sender<IPC>,
+}
+
+impl HashWorker {
+    fn run(&self) {
+        loop {
+            let job = {
+                let rx = self.rx.lock().unwrap();
+                rx.recv()
+            };
+            match job {
+                Ok(Job::Verify { piece_index, offset, length, hash }) => self.verify(piece_index, offset, length, hash),
+                Ok(Job::VerifyData { piece_index, data, hash }) => {
+                    let valid = calculate_sha1(&data) == hash;
+                    self.done(IPC::PieceVerified(piece_index, valid, if valid { Some(data) } else { None }));
+                },
+                Ok(Job::Shutdown) | Err(_) => return
+            }
+        }
+    }
+
+    // only the read happens inside the storage lock, so the hashing itself can use every core
+    fn verify(&self, piece_index: u32, offset: u64, length: u64, hash: Sha1) {
+        let result = {
+            let mut storage = self.storage.lock().unwrap();
+            storage.read(offset, length)
+        };
+        let valid = match result {
+            Ok(data) => calculate_sha1(&data) == hash,
+            Err(e) => {
+                println!("Error reading from disk: {:?}", e);
+                false
+            }
+        };
+        self.done(IPC::PieceVerified(piece_index, valid, None));
+    }
+
+    fn done(&self, ipc: IPC) {
+        match self.done_tx.send(ipc) {
+            Ok(_) => {},
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+}
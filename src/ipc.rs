@@ -1,10 +1,21 @@
+use std::net::IpAddr;
+
 use peer_connection::Message;
 
 #[derive(Clone)]
 pub enum IPC {
     BlockComplete(u32, u32),
+    BlockWritten(u32, u32, bool),
+    BlockRead(u32, u32, Option<Vec<u8>>),
+    PieceVerified(u32, bool, Option<Vec<u8>>),
+    PieceWritten(u32, bool),
     PieceComplete(u32),
+    PieceFailed(u32),
+    PeerBanned(IpAddr),
     DownloadComplete,
     Message(Message),
     BlockUploaded,
+    Choke,
+    Unchoke,
+    Shutdown,
 }
@@ -0,0 +1,252 @@
+use bencode::{Bencode, FromBencode, ToBencode};
+use bencode::util::ByteString;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use decoder;
+use hash::Sha1;
+use tracker_response::Peer;
+
+pub type NodeId = Vec<u8>;
+
+pub const NODE_ID_LENGTH: usize = 20;
+
+// nodes are sent as a 20 byte id followed by a compact IPv4 address and port
+const COMPACT_NODE_LENGTH: usize = 26;
+
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers(Sha1),
+    AnnouncePeer { info_hash: Sha1, port: u16, implied_port: bool, token: Vec<u8> },
+    Unknown(String),
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<Peer>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(i64, String),
+}
+
+// a KRPC message (BEP 5), which is a bencoded dictionary sent in a single UDP packet
+#[derive(PartialEq, Debug)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub sender_id: Option<NodeId>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn empty() -> Response {
+        Response { nodes: vec![], values: vec![], token: None }
+    }
+}
+
+impl Message {
+    pub fn query(transaction_id: Vec<u8>, sender_id: NodeId, query: Query) -> Message {
+        Message { transaction_id: transaction_id, sender_id: Some(sender_id), body: Body::Query(query) }
+    }
+
+    pub fn response(transaction_id: Vec<u8>, sender_id: NodeId, response: Response) -> Message {
+        Message { transaction_id: transaction_id, sender_id: Some(sender_id), body: Body::Response(response) }
+    }
+
+    pub fn error(transaction_id: Vec<u8>, code: i64, message: &str) -> Message {
+        Message { transaction_id: transaction_id, sender_id: None, body: Body::Error(code, message.to_string()) }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Message, decoder::Error> {
+        let bencode = try!(decoder::parse_untrusted(bytes));
+        let m = match bencode {
+            Bencode::Dict(ref m) => m,
+            _ => return Err(decoder::Error::NotADict)
+        };
+
+        let transaction_id = get_field_as_bytes!(m, "t");
+        let message_type: String = get_field!(m, "y");
+        let (sender_id, body) = match message_type.as_ref() {
+            "q" => {
+                let method: String = get_field!(m, "q");
+                let args = match get_raw_field!(m, "a") {
+                    &Bencode::Dict(ref a) => a,
+                    _ => return Err(decoder::Error::NotADict)
+                };
+                let query = match method.as_ref() {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode(get_field_as_bytes!(args, "target")),
+                    "get_peers" => Query::GetPeers(get_field_as_bytes!(args, "info_hash")),
+                    "announce_peer" => {
+                        let implied_port: u8 = get_field_with_default!(args, "implied_port", 0);
+                        Query::AnnouncePeer {
+                            info_hash: get_field_as_bytes!(args, "info_hash"),
+                            port: get_field_with_default!(args, "port", 0),
+                            implied_port: implied_port != 0,
+                            token: get_field_as_bytes!(args, "token"),
+                        }
+                    },
+                    _ => Query::Unknown(method.clone())
+                };
+                (Some(get_field_as_bytes!(args, "id")), Body::Query(query))
+            },
+            "r" => {
+                let r = match get_raw_field!(m, "r") {
+                    &Bencode::Dict(ref r) => r,
+                    _ => return Err(decoder::Error::NotADict)
+                };
+                let nodes = if r.contains_key(&ByteString::from_str("nodes")) {
+                    parse_compact_nodes(&get_field_as_bytes!(r, "nodes"))
+                } else {
+                    vec![]
+                };
+                let mut values = vec![];
+                if r.contains_key(&ByteString::from_str("values")) {
+                    for v in get_field_as_list!(r, "values").iter() {
+                        match v {
+                            &Bencode::ByteString(ref b) if b.len() == 6 => values.push(Peer::from_bytes(b)),
+                            &Bencode::ByteString(ref b) if b.len() == 18 => values.push(Peer::from_bytes_v6(b)),
+                            _ => println!("Ignoring DHT peer: {:?}", v)
+                        }
+                    }
+                }
+                let token = if r.contains_key(&ByteString::from_str("token")) {
+                    Some(get_field_as_bytes!(r, "token"))
+                } else {
+                    None
+                };
+                let response = Response { nodes: nodes, values: values, token: token };
+                (Some(get_field_as_bytes!(r, "id")), Body::Response(response))
+            },
+            "e" => {
+                let e = get_field_as_list!(m, "e");
+                let code = match e.get(0) {
+                    Some(c) => try!(FromBencode::from_bencode(c)),
+                    None => ERROR_PROTOCOL
+                };
+                let message = match e.get(1) {
+                    Some(s) => try!(FromBencode::from_bencode(s)),
+                    None => String::new()
+                };
+                (None, Body::Error(code, message))
+            },
+            _ => return Err(decoder::Error::DoesntContain("y"))
+        };
+
+        if let Some(ref id) = sender_id {
+            if id.len() != NODE_ID_LENGTH {
+                return Err(decoder::Error::DoesntContain("id"));
+            }
+        }
+
+        Ok(Message {
+            transaction_id: transaction_id,
+            sender_id: sender_id,
+            body: body,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, io::Error> {
+        let mut m = BTreeMap::new();
+        m.insert(ByteString::from_str("t"), Bencode::ByteString(self.transaction_id.clone()));
+
+        let mut args = BTreeMap::new();
+        if let Some(ref id) = self.sender_id {
+            args.insert(ByteString::from_str("id"), Bencode::ByteString(id.clone()));
+        }
+
+        match self.body {
+            Body::Query(ref query) => {
+                let method = match *query {
+                    Query::Ping => "ping",
+                    Query::FindNode(ref target) => {
+                        args.insert(ByteString::from_str("target"), Bencode::ByteString(target.clone()));
+                        "find_node"
+                    },
+                    Query::GetPeers(ref info_hash) => {
+                        args.insert(ByteString::from_str("info_hash"), Bencode::ByteString(info_hash.clone()));
+                        "get_peers"
+                    },
+                    Query::AnnouncePeer { ref info_hash, port, implied_port, ref token } => {
+                        args.insert(ByteString::from_str("info_hash"), Bencode::ByteString(info_hash.clone()));
+                        args.insert(ByteString::from_str("port"), port.to_bencode());
+                        args.insert(ByteString::from_str("implied_port"), (implied_port as u8).to_bencode());
+                        args.insert(ByteString::from_str("token"), Bencode::ByteString(token.clone()));
+                        "announce_peer"
+                    },
+                    Query::Unknown(ref method) => method.as_ref(),
+                };
+                m.insert(ByteString::from_str("y"), "q".to_string().to_bencode());
+                m.insert(ByteString::from_str("q"), method.to_string().to_bencode());
+                m.insert(ByteString::from_str("a"), Bencode::Dict(args));
+            },
+            Body::Response(ref response) => {
+                if response.nodes.len() > 0 {
+                    args.insert(ByteString::from_str("nodes"), Bencode::ByteString(compact_nodes(&response.nodes)));
+                }
+                if response.values.len() > 0 {
+                    let values = response.values.iter().map(|p| Bencode::ByteString(compact_addr(&p.addr()))).collect();
+                    args.insert(ByteString::from_str("values"), Bencode::List(values));
+                }
+                if let Some(ref token) = response.token {
+                    args.insert(ByteString::from_str("token"), Bencode::ByteString(token.clone()));
+                }
+                m.insert(ByteString::from_str("y"), "r".to_string().to_bencode());
+                m.insert(ByteString::from_str("r"), Bencode::Dict(args));
+            },
+            Body::Error(code, ref message) => {
+                m.insert(ByteString::from_str("y"), "e".to_string().to_bencode());
+                m.insert(ByteString::from_str("e"), Bencode::List(vec![Bencode::Number(code), message.to_bencode()]));
+            }
+        }
+
+        Bencode::Dict(m).to_bytes()
+    }
+}
+
+pub fn parse_compact_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes.chunks(COMPACT_NODE_LENGTH).filter(|c| c.len() == COMPACT_NODE_LENGTH).map(|c| {
+        NodeInfo { id: c[0..NODE_ID_LENGTH].to_owned(), addr: Peer::from_bytes(&c[NODE_ID_LENGTH..]).addr() }
+    }).collect()
+}
+
+// only IPv4 nodes fit in the compact format, so any others are skipped
+pub fn compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for node in nodes.iter().filter(|n| n.addr.is_ipv4()) {
+        bytes.extend(node.id.iter().cloned());
+        bytes.extend(compact_addr(&node.addr));
+    }
+    bytes
+}
+
+fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => ip.octets().to_vec(),
+            None => ip.octets().to_vec()
+        }
+    };
+    bytes.push((addr.port() >> 8) as u8);
+    bytes.push(addr.port() as u8);
+    bytes
+}
@@ -3,24 +3,26 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 
+use choker::Choker;
 use download::Download;
 use peer_connection;
+use peer_pool::PeerPool;
 
-pub fn start(port: u16, download_mutex: Arc<Mutex<Download>>) -> JoinHandle<()> {
+pub fn start(port: u16, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) -> JoinHandle<()> {
     let tcp_listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
     thread::spawn(move || {
         for stream in tcp_listener.incoming() {
             match stream {
-                Ok(s) => handle_connection(s, download_mutex.clone()),
+                Ok(s) => handle_connection(s, download_mutex.clone(), peer_pool.clone(), choker_mutex.clone()),
                 Err(e) => println!("Error: {:?}", e)
             }
         }
     })
 }
 
-fn handle_connection(stream: TcpStream, download_mutex: Arc<Mutex<Download>>) {
+fn handle_connection(stream: TcpStream, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) {
     thread::spawn(move || {
-        match peer_connection::accept(stream, download_mutex) {
+        match peer_connection::accept(stream, download_mutex, peer_pool, choker_mutex) {
             Ok(_) => println!("Peer done"),
             Err(e) => println!("Error: {:?}", e)
         }
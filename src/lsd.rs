@@ -0,0 +1,192 @@
+use net2::UdpBuilder;
+use rand;
+use rand::Rng;
+use std::{io, thread};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use hash::Sha1;
+use ipc::IPC;
+use peer_pool::PeerPool;
+use tracker_response::Peer;
+
+// the multicast group and port for Local Service Discovery (BEP 14)
+const MULTICAST_ADDR: [u8; 4] = [239, 192, 152, 143];
+const MULTICAST_PORT: u16 = 6771;
+
+// announce every 5 minutes
+const ANNOUNCE_INTERVAL_SECS: u64 = 5 * 60;
+
+const POLL_INTERVAL_MILLIS: u64 = 500;
+const MAX_PACKET_SIZE: usize = 1500;
+
+pub struct Lsd {
+    tx: Sender<IPC>,
+    thread: JoinHandle<()>,
+}
+
+impl Lsd {
+    // announce the torrent to the local network, and listen for other peers on it doing the same
+    pub fn start(listener_port: u16, info_hash: Sha1, peer_pool: Arc<Mutex<PeerPool>>) -> Result<Lsd, io::Error> {
+        let group = Ipv4Addr::new(MULTICAST_ADDR[0], MULTICAST_ADDR[1], MULTICAST_ADDR[2], MULTICAST_ADDR[3]);
+
+        // every client on the machine listens on the LSD port, so it has to be shared. if we still can't get it (e.g.
+        // another client didn't allow sharing), or can't join the group (e.g. there's no multicast route), we can at
+        // least announce
+        let listening = bind_shared(MULTICAST_PORT).and_then(|s| {
+            try!(s.join_multicast_v4(&group, &Ipv4Addr::new(0, 0, 0, 0)));
+            Ok(s)
+        });
+        let socket = match listening {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Not listening for local peers: {:?}", e);
+                try!(UdpSocket::bind("0.0.0.0:0"))
+            }
+        };
+        try!(socket.set_multicast_loop_v4(true));
+        try!(socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MILLIS))));
+
+        let (tx, rx) = channel::<IPC>();
+        let thread = thread::spawn(move || {
+            let mut lsd_loop = LsdLoop {
+                socket: socket,
+                group: SocketAddrV4::new(group, MULTICAST_PORT),
+                listener_port: listener_port,
+                info_hash: hex(&info_hash),
+                cookie: rand::thread_rng().gen_ascii_chars().take(8).collect(),
+                peer_pool: peer_pool,
+            };
+            lsd_loop.run(rx);
+        });
+
+        Ok(Lsd {
+            tx: tx,
+            thread: thread,
+        })
+    }
+
+    pub fn stop(self) {
+        match self.tx.send(IPC::Shutdown) {
+            Ok(_) => {
+                match self.thread.join() {
+                    Ok(_) => {},
+                    Err(e) => println!("Error: {:?}", e)
+                }
+            },
+            Err(e) => println!("Error: {:?}", e)
+        }
+    }
+}
+
+struct LsdLoop {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    listener_port: u16,
+    info_hash: String,
+    cookie: String,
+    peer_pool: Arc<Mutex<PeerPool>>,
+}
+
+impl LsdLoop {
+    fn run(&mut self, rx: Receiver<IPC>) {
+        let mut next_announce = Instant::now();
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            match rx.try_recv() {
+                Ok(IPC::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                Ok(_) | Err(TryRecvError::Empty) => {}
+            }
+
+            if Instant::now() >= next_announce {
+                self.announce();
+                next_announce = Instant::now() + Duration::from_secs(ANNOUNCE_INTERVAL_SECS);
+            }
+
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, from)) => self.receive(&buf[..size], from),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => println!("LSD error: {:?}", e)
+            }
+        }
+    }
+
+    fn announce(&self) {
+        let message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+                              self.group, self.listener_port, self.info_hash, self.cookie);
+        match self.socket.send_to(message.as_bytes(), self.group) {
+            Ok(_) => {},
+            Err(e) => println!("Error announcing to local peers: {:?}", e)
+        }
+    }
+
+    fn receive(&self, bytes: &[u8], from: SocketAddr) {
+        let announce = match Announce::parse(bytes) {
+            Some(a) => a,
+            None => return
+        };
+
+        // our own announces come back to us, and other torrents are none of our business
+        if announce.cookie.as_ref() == Some(&self.cookie) || !announce.info_hashes.contains(&self.info_hash) {
+            return;
+        }
+
+        let peer = Peer::new(from.ip(), announce.port);
+        let added = {
+            let mut peer_pool = self.peer_pool.lock().unwrap();
+            peer_pool.add(vec![peer])
+        };
+        if added > 0 {
+            println!("Found a local peer at {}:{}", from.ip(), announce.port);
+        }
+    }
+}
+
+struct Announce {
+    port: u16,
+    info_hashes: Vec<String>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    // messages look like HTTP requests, with a header for each info hash
+    fn parse(bytes: &[u8]) -> Option<Announce> {
+        let text = String::from_utf8_lossy(bytes);
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines {
+            let i = match line.find(':') {
+                Some(i) => i,
+                None => continue
+            };
+            let value = line[(i + 1)..].trim();
+            match line[..i].trim().to_lowercase().as_ref() {
+                "port" => port = value.parse().ok(),
+                "infohash" => info_hashes.push(value.to_lowercase()),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        port.map(|p| Announce { port: p, info_hashes: info_hashes, cookie: cookie })
+    }
+}
+
+fn bind_shared(port: u16) -> Result<UdpSocket, io::Error> {
+    let builder = try!(UdpBuilder::new_v4());
+    try!(builder.reuse_address(true));
+    builder.bind(("0.0.0.0", port))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
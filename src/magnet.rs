@@ -0,0 +1,103 @@
+extern crate url;
+
+use std::convert;
+use std::num::ParseIntError;
+use self::url::form_urlencoded;
+
+use hash::Sha1;
+
+const PREFIX: &'static str = "magnet:?";
+const BTIH_PREFIX: &'static str = "urn:btih:";
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(PartialEq, Debug)]
+pub struct Magnet {
+    pub info_hash: Sha1,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Magnet, Error> {
+        if !is_magnet(uri) {
+            return Err(Error::NotAMagnetLink);
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        for (key, value) in form_urlencoded::parse(uri[PREFIX.len()..].as_bytes()).into_iter() {
+            match key.as_ref() {
+                "xt" if value.starts_with(BTIH_PREFIX) => info_hash = Some(try!(decode_info_hash(&value[BTIH_PREFIX.len()..]))),
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        match info_hash {
+            Some(h) => Ok(Magnet { info_hash: h, display_name: display_name, trackers: trackers }),
+            None => Err(Error::MissingInfoHash)
+        }
+    }
+
+    // every tracker gets its own tier, so that we hear from all of them
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|t| vec![t.clone()]).collect()
+    }
+}
+
+pub fn is_magnet(uri: &str) -> bool {
+    uri.starts_with(PREFIX)
+}
+
+// info hashes are either 40 hex characters or 32 base32 characters
+fn decode_info_hash(s: &str) -> Result<Sha1, Error> {
+    if !s.is_ascii() {
+        return Err(Error::InvalidInfoHash(s.to_string()));
+    }
+
+    match s.len() {
+        40 => {
+            let mut hash = vec![];
+            for i in 0..20 {
+                hash.push(try!(u8::from_str_radix(&s[(i * 2)..(i * 2 + 2)], 16)));
+            }
+            Ok(hash)
+        },
+        32 => {
+            let mut hash = vec![];
+            let mut buffer: u32 = 0;
+            let mut bits = 0;
+            for c in s.to_uppercase().bytes() {
+                let value = match BASE32_ALPHABET.iter().position(|&b| b == c) {
+                    Some(v) => v as u32,
+                    None => return Err(Error::InvalidInfoHash(s.to_string()))
+                };
+                buffer = (buffer << 5) | value;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    hash.push((buffer >> bits) as u8);
+                    buffer &= (1 << bits) - 1;
+                }
+            }
+            Ok(hash)
+        },
+        _ => Err(Error::InvalidInfoHash(s.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotAMagnetLink,
+    MissingInfoHash,
+    InvalidInfoHash(String),
+    ParseIntError(ParseIntError),
+}
+
+impl convert::From<ParseIntError> for Error {
+    fn from(err: ParseIntError) -> Error {
+        Error::ParseIntError(err)
+    }
+}
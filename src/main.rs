@@ -1,35 +1,88 @@
 extern crate bencode;
+extern crate ctrlc;
 extern crate getopts;
+extern crate net2;
+extern crate num_cpus;
 extern crate rand;
 
+mod announcer;
+mod choker;
+mod console;
 mod decoder;
+mod disk;
+mod dht;
 mod download;
+mod extension;
 mod hash;
+mod hasher;
 mod ipc;
+mod krpc;
 mod listener;
+mod lsd;
+mod magnet;
 mod metainfo;
 mod peer_connection;
+mod peer_pool;
+mod pipeline;
+mod pex;
+mod rate_limiter;
+mod read_cache;
 mod request_metadata;
 mod request_queue;
+mod resume;
+mod routing_table;
+mod scrape_response;
+mod storage;
 mod tracker;
 mod tracker_response;
+mod udp_tracker;
+mod ut_metadata;
 
 use getopts::Options;
 use rand::Rng;
-use std::{any, convert, env, process, thread};
+use std::{any, convert, env, io, process, thread};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use download::Download;
+use announcer::Announcer;
+use choker::Choker;
+use dht::Dht;
+use download::{BLOCK_SIZE, Download, Stats};
+use ipc::IPC;
+use lsd::Lsd;
+use magnet::Magnet;
+use metainfo::Metainfo;
+use peer_pool::PeerPool;
+use rate_limiter::RateLimits;
+use read_cache::ReadCache;
+use tracker::{Event, Tracker};
+use tracker_response::Peer;
 
 const PEER_ID_PREFIX: &'static str = "-RC0001-";
 
+// how much of the torrent to keep in memory for uploading, in MiB
+const DEFAULT_READ_CACHE_SIZE: u64 = 32;
+
+// how often to save our progress while downloading, so not much is lost if we don't get to stop cleanly
+const CHECKPOINT_INTERVAL_SECS: u64 = 60;
+
 fn main() {
     // parse command-line arguments & options
     let args: Vec<String> = env::args().collect();
     let program = &args[0];
     let mut opts = Options::new();
     opts.optopt("p", "port", "set listen port to", "6881");
+    opts.optflag("", "no-dht", "don't use the DHT to find peers");
+    opts.optmulti("", "dht-bootstrap", "join the DHT through this node, instead of the well-known routers", "HOST:PORT");
+    opts.optflag("", "no-lsd", "don't look for peers on the local network");
+    opts.optopt("u", "upload-limit", "limit the total upload rate, in KiB/s", "100");
+    opts.optopt("d", "download-limit", "limit the total download rate, in KiB/s", "500");
+    opts.optopt("", "torrent-upload-limit", "limit the torrent's upload rate, in KiB/s", "100");
+    opts.optopt("", "torrent-download-limit", "limit the torrent's download rate, in KiB/s", "500");
+    opts.optopt("", "read-cache", "keep this many MiB of recently uploaded pieces in memory (0 turns it off)", "32");
+    opts.optflag("s", "scrape", "print the number of seeders and leechers for the torrent, without downloading it");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -52,20 +105,69 @@ fn main() {
         None => 6881
     };
 
+    let dht_bootstrap = if matches.opt_present("no-dht") {
+        None
+    } else {
+        let nodes = matches.opt_strs("dht-bootstrap");
+        if nodes.len() > 0 {
+            Some(nodes)
+        } else {
+            Some(dht::BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect())
+        }
+    };
+
+    let mut limits = vec![];
+    for opt in ["upload-limit", "download-limit", "torrent-upload-limit", "torrent-download-limit"].iter() {
+        match matches.opt_str(opt) {
+            Some(rate_string) => {
+                match rate_limiter::parse_rate(&rate_string) {
+                    Ok(r) => limits.push(r),
+                    Err(e) => return abort(&program, opts, format!("Bad rate limit {}: {:?}", rate_string, e))
+                }
+            },
+            None => limits.push(None)
+        }
+    }
+    let global_limits = RateLimits::new(limits[0], limits[1]);
+    let torrent_limits = RateLimits::new(limits[2], limits[3]);
+
+    let read_cache_size = match matches.opt_str("read-cache") {
+        Some(size_string) => {
+            let size: Result<u64,_> = size_string.parse();
+            match size {
+                Ok(s) => s,
+                Err(_) => return abort(&program, opts, format!("Bad read cache size: {}", size_string))
+            }
+        },
+        None => DEFAULT_READ_CACHE_SIZE
+    };
+    let read_cache_bytes = match read_cache_size.checked_mul(1024 * 1024) {
+        Some(bytes) => bytes,
+        None => return abort(&program, opts, format!("Bad read cache size: {}", read_cache_size))
+    };
+    let read_cache = Arc::new(Mutex::new(ReadCache::new(read_cache_bytes)));
+
+    let use_lsd = !matches.opt_present("no-lsd");
+    let scrape_only = matches.opt_present("s");
     let rest = matches.free;
     if rest.len() != 1 {
         abort(&program, opts, format!("You must provide exactly 1 argument to rusty_torrent: {:?}", rest))
     }
 
     let filename = &rest[0];
-    match run(filename, port) {
+    let result = if scrape_only {
+        scrape(filename)
+    } else {
+        run(filename, port, dht_bootstrap, use_lsd, global_limits, torrent_limits, read_cache)
+    };
+    match result {
         Ok(_) => {},
         Err(e) => println!("Error: {:?}", e)
     }
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options] path/to/myfile.torrent", program);
+    let brief = format!("Usage: {} [options] (path/to/myfile.torrent | magnet:?xt=urn:btih:...)", program);
     print!("{}", opts.usage(&brief));
 }
 
@@ -75,40 +177,238 @@ fn abort(program: &str, opts: Options, err: String) {
     process::exit(1);
 }
 
-fn run(filename: &str, listener_port: u16) -> Result<(), Error> {
+// dht_bootstrap is None if the DHT shouldn't be used
+fn run(target: &str, listener_port: u16, dht_bootstrap: Option<Vec<String>>, use_lsd: bool, global_limits: RateLimits, torrent_limits: RateLimits, read_cache: Arc<Mutex<ReadCache>>) -> Result<(), Error> {
     let our_peer_id = generate_peer_id();
     println!("Using peer id: {}", our_peer_id);
 
-    // parse .torrent file
-    let metainfo = try!(metainfo::parse(filename));
-
-    // connect to tracker and download list of peers
-    let peers = try!(tracker::get_peers(&our_peer_id, &metainfo, listener_port));
-    println!("Found {} peers", peers.len());
+    // parse .torrent file, or fetch the torrent's metadata from peers for a magnet link
+    let metainfo = if magnet::is_magnet(target) {
+        try!(fetch_metainfo(target, &our_peer_id, listener_port, dht_bootstrap.clone()))
+    } else {
+        try!(metainfo::parse(target))
+    };
 
     // create the download metadata object and stuff it inside a reference-counted mutex
-    let download = try!(Download::new(our_peer_id, metainfo));
+    let info_hash = metainfo.info_hash.clone();
+    let tracker = Tracker::new(metainfo.info_hash.clone(), metainfo.tracker_tiers());
+    let (disk_done_tx, disk_done_rx) = channel::<IPC>();
+    let download = try!(Download::new(our_peer_id.clone(), listener_port, metainfo, vec![global_limits.clone(), torrent_limits.clone()], read_cache.clone(), disk_done_tx));
+    let stats = download.stats();
     let download_mutex = Arc::new(Mutex::new(download));
 
+    // spawn thread to keep the download up to date with what has been written to disk
+    let disk_io_thread = download::handle_disk_io(download_mutex.clone(), disk_done_rx);
+
+    // create the pool of peers, which sends any new peers it hears about to us
+    let (peer_tx, peer_rx) = channel::<Peer>();
+    let peer_pool_mutex = Arc::new(Mutex::new(PeerPool::new(peer_tx)));
+
+    // join the DHT, which looks for peers and announces us (using the same port number as the listener, but over UDP)
+    let dht = match dht_bootstrap {
+        Some(nodes) => match Dht::start(listener_port, info_hash.clone(), listener_port, nodes, peer_pool_mutex.clone()) {
+            Ok(dht) => Some(dht),
+            Err(e) => {
+                // e.g. another client's DHT node has the port. there are still other ways of finding peers
+                println!("Not joining the DHT: {:?}", e);
+                None
+            }
+        },
+        None => None
+    };
+
+    // spawn thread to read commands for changing the rate limits, showing the read cache's stats, or quitting
+    let (quit_tx, quit_rx) = channel::<()>();
+    console::start(global_limits, torrent_limits, read_cache, quit_tx.clone());
+
+    // ctrl-c quits the same way, so our progress is saved. a second one doesn't wait for that
+    let mut interrupted = false;
+    let result = ctrlc::set_handler(move || {
+        if interrupted {
+            process::exit(1);
+        }
+        interrupted = true;
+        match quit_tx.send(()) {
+            Ok(_) => {},
+            Err(e) => println!("Error: {:?}", e)
+        }
+    });
+    if let Err(e) = result {
+        println!("Error handling ctrl-c: {:?}", e);
+    }
+
+    // spawn thread to periodically decide which peers to upload to
+    let choker_mutex = Arc::new(Mutex::new(Choker::new()));
+    choker::start(choker_mutex.clone(), download_mutex.clone());
+
     // spawn thread to listen for incoming request
-    listener::start(listener_port, download_mutex.clone());
-
-    // spawn threads to connect to peers and start the download
-    let peer_threads: Vec<JoinHandle<()>> = peers.into_iter().map(|peer| {
-        let mutex = download_mutex.clone();
-        thread::spawn(move || {
-            match peer_connection::connect(&peer, mutex) {
-                Ok(_) => println!("Peer done"),
-                Err(e) => println!("Error: {:?}", e)
+    listener::start(listener_port, download_mutex.clone(), peer_pool_mutex.clone(), choker_mutex.clone());
+
+    // look for peers downloading the same torrent on the local network, now that they can connect to us
+    let lsd = if use_lsd {
+        match Lsd::start(listener_port, info_hash, peer_pool_mutex.clone()) {
+            Ok(lsd) => Some(lsd),
+            Err(e) => {
+                println!("Not looking for local peers: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // spawn thread to announce to the trackers, and periodically re-announce (if the trackers are dead, hopefully the
+    // DHT will find some peers)
+    let announcer = Announcer::start(tracker, our_peer_id, listener_port, download_mutex.clone(), peer_pool_mutex.clone());
+
+    // spawn threads to connect to peers as they are discovered, until the download completes (or forever, if we're seeding)
+    let seeding = stats.left == 0;
+    let mut peer_threads: Vec<JoinHandle<()>> = vec![];
+    let mut checkpoint_at = Instant::now();
+    loop {
+        let received = peer_rx.recv_timeout(Duration::from_secs(1));
+
+        // once the download completes, stop connecting to new peers
+        let checkpoint = {
+            let mut download = download_mutex.lock().unwrap();
+            if !seeding && download.is_complete() {
+                break;
+            }
+            if quit_rx.try_recv().is_ok() {
+                println!("Quitting");
+                download.shutdown();
+                break;
+            }
+            if !seeding && checkpoint_at.elapsed() >= Duration::from_secs(CHECKPOINT_INTERVAL_SECS) {
+                checkpoint_at = Instant::now();
+                Some(download.checkpoint())
+            } else {
+                None
             }
-        })
-    }).collect();
+        };
+        if let Some(checkpoint) = checkpoint {
+            if let Err(e) = checkpoint.save() {
+                println!("Error saving progress: {:?}", e);
+            }
+        }
+
+        match received {
+            Ok(peer) => {
+                let mutex = download_mutex.clone();
+                let peer_pool = peer_pool_mutex.clone();
+                let choker = choker_mutex.clone();
+                peer_threads.push(thread::spawn(move || {
+                    match peer_connection::connect(&peer, mutex, peer_pool.clone(), choker) {
+                        Ok(_) => println!("Peer done"),
+                        Err(e) => println!("Error: {:?}", e)
+                    }
+                    // frees up the connection for the next waiting peer, however far this one got
+                    peer_pool.lock().unwrap().disconnected(&peer.addr());
+                }));
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+    }
 
     // wait for peers to complete
     for thr in peer_threads {
         try!(thr.join());
     }
 
+    // once everything has been written to the files, we can save our progress
+    download::finish_disk_io(&download_mutex);
+    try!(disk_io_thread.join());
+    {
+        let download = download_mutex.lock().unwrap();
+        try!(download.save_resume());
+    }
+
+    // let the trackers know we're done
+    announcer.stop();
+    if let Some(dht) = dht {
+        dht.stop();
+    }
+    if let Some(lsd) = lsd {
+        lsd.stop();
+    }
+
+    Ok(())
+}
+
+// dht_bootstrap is None if the DHT shouldn't be used
+fn fetch_metainfo(uri: &str, our_peer_id: &str, listener_port: u16, dht_bootstrap: Option<Vec<String>>) -> Result<Metainfo, Error> {
+    let magnet = try!(Magnet::parse(uri));
+    println!("Fetching metadata for {}", magnet.display_name.as_ref().unwrap_or(&uri.to_string()));
+
+    // peers from the trackers and the DHT are asked for the metadata as they turn up. only the peer sources hold on to
+    // the pool, so once they're all done, the fetch knows that no more peers are coming. that also means nothing can
+    // tell the pool when a fetcher is done with its peer, so the pool can't limit how many are tried at once
+    let (peer_tx, peer_rx) = channel::<Peer>();
+    let peer_pool_mutex = Arc::new(Mutex::new(PeerPool::unlimited(peer_tx)));
+    let dht = match dht_bootstrap {
+        Some(nodes) => match Dht::start(listener_port, magnet.info_hash.clone(), listener_port, nodes, peer_pool_mutex.clone()) {
+            Ok(dht) => Some(dht),
+            Err(e) => {
+                // e.g. another client's DHT node has the port. there are still other ways of finding peers
+                println!("Not joining the DHT: {:?}", e);
+                None
+            }
+        },
+        None => None
+    };
+
+    // we don't know how big the torrent is yet, but telling the tracker we have nothing left could get us no seeders
+    let stats = Stats { uploaded: 0, downloaded: 0, left: BLOCK_SIZE as u64 };
+    let mut tracker = Tracker::new(magnet.info_hash.clone(), magnet.tracker_tiers());
+    let tracker_peer_id = our_peer_id.to_string();
+    thread::spawn(move || {
+        match tracker.get_peers(&tracker_peer_id, listener_port, stats, Event::Started) {
+            Ok(peers) => {
+                println!("Found {} peers", peers.len());
+                peer_pool_mutex.lock().unwrap().add(peers);
+            },
+            Err(e) => println!("Error: {:?}", e)
+        }
+    });
+
+    let result = ut_metadata::fetch(&magnet.info_hash, our_peer_id, listener_port, peer_rx);
+
+    // the download starts its own DHT node, on the same port
+    if let Some(dht) = dht {
+        dht.stop();
+    }
+
+    let info_bytes = try!(result);
+    let metainfo = try!(Metainfo::from_info_bytes(&info_bytes, magnet.tracker_tiers()));
+    Ok(metainfo)
+}
+
+fn scrape(target: &str) -> Result<(), Error> {
+    // parse .torrent file or magnet link
+    let (info_hash, tiers) = if magnet::is_magnet(target) {
+        let magnet = try!(Magnet::parse(target));
+        let tiers = magnet.tracker_tiers();
+        (magnet.info_hash, tiers)
+    } else {
+        let metainfo = try!(metainfo::parse(target));
+        let tiers = metainfo.tracker_tiers();
+        (metainfo.info_hash, tiers)
+    };
+
+    // ask every tracker for its view of the swarm
+    for tier in tiers.iter() {
+        for url in tier.iter() {
+            match tracker::scrape(url, &[info_hash.clone()]) {
+                Ok(res) => match res.files.get(&info_hash) {
+                    Some(stats) => println!("{}: {} seeders, {} leechers, {} completed", url, stats.complete, stats.incomplete, stats.downloaded),
+                    None => println!("{}: torrent not found", url)
+                },
+                Err(e) => println!("{}: Error: {:?}", url, e)
+            }
+        }
+    }
+
     Ok(())
 }
 
@@ -121,7 +421,11 @@ fn generate_peer_id() -> String {
 #[derive(Debug)]
 pub enum Error {
     DecoderError(decoder::Error),
+    DhtError(dht::Error),
     DownloadError(download::Error),
+    IoError(io::Error),
+    MagnetError(magnet::Error),
+    MetadataError(ut_metadata::Error),
     TrackerError(tracker::Error),
     Any(Box<any::Any + Send>),
 }
@@ -132,12 +436,36 @@ impl convert::From<decoder::Error> for Error {
     }
 }
 
+impl convert::From<dht::Error> for Error {
+    fn from(err: dht::Error) -> Error {
+        Error::DhtError(err)
+    }
+}
+
 impl convert::From<download::Error> for Error {
     fn from(err: download::Error) -> Error {
         Error::DownloadError(err)
     }
 }
 
+impl convert::From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl convert::From<magnet::Error> for Error {
+    fn from(err: magnet::Error) -> Error {
+        Error::MagnetError(err)
+    }
+}
+
+impl convert::From<ut_metadata::Error> for Error {
+    fn from(err: ut_metadata::Error) -> Error {
+        Error::MetadataError(err)
+    }
+}
+
 impl convert::From<tracker::Error> for Error {
     fn from(err: tracker::Error) -> Error {
         Error::TrackerError(err)
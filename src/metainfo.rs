@@ -3,6 +3,7 @@ use bencode::{Bencode, FromBencode};
 use bencode::util::ByteString;
 use std::fs::File;
 use std::io::Read;
+use std::path::PathBuf;
 
 use decoder;
 use hash::{calculate_sha1, Sha1};
@@ -10,6 +11,7 @@ use hash::{calculate_sha1, Sha1};
 #[derive(PartialEq, Debug)]
 pub struct Metainfo {
     pub announce: String,
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
     pub info_hash: Vec<u8>,
     pub created_by: String,
@@ -24,8 +26,30 @@ impl FromBencode for Metainfo {
                 let info_bytes = get_field_as_bencoded_bytes!(m, "info");
                 let info_hash = calculate_sha1(&info_bytes);
 
+                // announce-list is a list of tiers, each of which is a list of tracker URLs (BEP 12)
+                let mut announce_list = vec![];
+                if m.contains_key(&ByteString::from_str("announce-list")) {
+                    for t in get_field_as_list!(m, "announce-list").iter() {
+                        let tier = match t {
+                            &Bencode::List(ref urls) => {
+                                let mut tier = vec![];
+                                for url in urls.iter() {
+                                    let url: String = try!(FromBencode::from_bencode(url));
+                                    tier.push(url);
+                                }
+                                tier
+                            },
+                            _ => return Err(decoder::Error::NotAList)
+                        };
+                        if tier.len() > 0 {
+                            announce_list.push(tier);
+                        }
+                    }
+                }
+
                 let metainfo = Metainfo{
-                    announce: get_field!(m, "announce"),
+                    announce: get_field_with_default!(m, "announce", "".to_string()),
+                    announce_list: announce_list,
                     info: get_field!(m, "info"),
                     info_hash: info_hash,
                     created_by: get_field_with_default!(m, "created by", "".to_string()),
@@ -37,6 +61,34 @@ impl FromBencode for Metainfo {
     }
 }
 
+impl Metainfo {
+    // build a Metainfo from an info dictionary downloaded from peers (e.g. for a magnet link)
+    pub fn from_info_bytes(info_bytes: &[u8], announce_list: Vec<Vec<String>>) -> Result<Metainfo, decoder::Error> {
+        let bencode = try!(bencode::from_buffer(info_bytes));
+        let info = try!(FromBencode::from_bencode(&bencode));
+
+        let metainfo = Metainfo {
+            announce: "".to_string(),
+            announce_list: announce_list,
+            info: info,
+            info_hash: calculate_sha1(info_bytes),
+            created_by: "".to_string(),
+        };
+        Ok(metainfo)
+    }
+
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        // clients that support announce-list must ignore announce when it's present
+        if self.announce_list.len() > 0 {
+            self.announce_list.clone()
+        } else if self.announce.len() > 0 {
+            vec![vec![self.announce.clone()]]
+        } else {
+            vec![]
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Info {
     pub piece_length: u32,
@@ -44,6 +96,7 @@ pub struct Info {
     pub num_pieces: u32,
     pub name: String,
     pub length: u64,
+    pub files: Vec<FileInfo>,
 }
 
 impl FromBencode for Info {
@@ -55,13 +108,32 @@ impl FromBencode for Info {
                 let pieces_bytes = get_field_as_bytes!(m, "pieces");
                 let pieces: Vec<Sha1> = pieces_bytes.chunks(20).map(|v| v.to_owned()).collect();
                 let num_pieces = pieces.len() as u32;
+                let name: String = get_field!(m, "name");
+                if name == ".." || name.contains('/') {
+                    return Err(decoder::Error::UnsafePath(name));
+                }
+
+                // single-file torrents have a top-level length, multi-file torrents have a list of files
+                let files = match m.get(&ByteString::from_str("files")) {
+                    Some(_) => {
+                        let mut files = vec![];
+                        for f in get_field_as_list!(m, "files").iter() {
+                            let file: FileInfo = try!(FromBencode::from_bencode(f));
+                            files.push(file.prefixed_with(&name));
+                        }
+                        files
+                    },
+                    None => vec![FileInfo { length: get_field!(m, "length"), path: PathBuf::from(&name) }]
+                };
+                let length = files.iter().map(|f| f.length).sum();
 
                 let info = Info {
                     piece_length: get_field!(m, "piece length"),
                     pieces: pieces,
                     num_pieces: num_pieces,
-                    name: get_field!(m, "name"),
-                    length: get_field!(m, "length"),
+                    name: name,
+                    length: length,
+                    files: files,
                 };
                 Ok(info)
             }
@@ -70,6 +142,44 @@ impl FromBencode for Info {
     }
 }
 
+#[derive(PartialEq, Debug)]
+pub struct FileInfo {
+    pub length: u64,
+    pub path: PathBuf,
+}
+
+impl FileInfo {
+    fn prefixed_with(self, dir: &str) -> FileInfo {
+        FileInfo { length: self.length, path: PathBuf::from(dir).join(self.path) }
+    }
+}
+
+impl FromBencode for FileInfo {
+    type Err = decoder::Error;
+
+    fn from_bencode(bencode: &bencode::Bencode) -> Result<FileInfo, decoder::Error> {
+        match bencode {
+            &Bencode::Dict(ref m) => {
+                let mut path = PathBuf::new();
+                for component in get_field_as_list!(m, "path").iter() {
+                    let component: String = try!(FromBencode::from_bencode(component));
+                    if component == ".." || component.contains('/') {
+                        return Err(decoder::Error::UnsafePath(component));
+                    }
+                    path.push(component);
+                }
+
+                let file = FileInfo {
+                    length: get_field!(m, "length"),
+                    path: path,
+                };
+                Ok(file)
+            }
+            _ => Err(decoder::Error::NotADict)
+        }
+    }
+}
+
 pub fn parse(filename: &str) -> Result<Metainfo, decoder::Error> {
     println!("Loading {}", filename);
 
@@ -1,54 +1,93 @@
-use rand;
-use rand::Rng;
-use std::{any, convert, fmt, io, thread};
-use std::collections::HashMap;
+use bencode::ToBencode;
+use std::{any, cmp, convert, fmt, io, thread};
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, RecvError, Sender, SendError};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender, SendError};
+use std::time::{Duration, Instant};
 
+use choker::Choker;
+use decoder;
 use download;
-use download::{BLOCK_SIZE, Download};
+use download::{BLOCK_SIZE, Download, PeerIdentity};
+use extension;
+use extension::ExtendedHandshake;
 use ipc::IPC;
+use peer_pool::PeerPool;
+use pex;
+use pipeline::Pipeline;
+use rate_limiter;
+use rate_limiter::RateLimiter;
 use tracker_response::Peer;
 use request_queue::RequestQueue;
+use ut_metadata;
 
-const PROTOCOL: &'static str = "BitTorrent protocol";
-const MAX_CONCURRENT_REQUESTS: u32 = 10;
+pub const PROTOCOL: &'static str = "BitTorrent protocol";
+const MAX_QUEUED_UPLOADS: u32 = 250;
+const TICK_INTERVAL_SECS: u64 = 1;
 
-pub fn connect(peer: &Peer, download_mutex: Arc<Mutex<Download>>) -> Result<(), Error> {
-    PeerConnection::connect(peer, download_mutex)
+// the largest message we accept, which leaves room for a block and its Piece header, or a block of metadata in an
+// extended message. a bitfield can be bigger than this, for torrents with a lot of pieces
+const MAX_MESSAGE_SIZE: u32 = BLOCK_SIZE + 1024;
+
+// give up on a request that hasn't been answered in this long, so another peer can have a go at the block
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+// and leave it to other peers for this long, before trying this one again
+const TIMED_OUT_COOLDOWN_SECS: u64 = 60;
+
+// a peer that doesn't answer in this long isn't worth waiting for. until the handshake is done, the connection can't be
+// told to shut down, so it mustn't be able to wait forever
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+pub fn connect(peer: &Peer, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) -> Result<(), Error> {
+    PeerConnection::connect(peer, download_mutex, peer_pool, choker_mutex)
 }
 
-pub fn accept(stream: TcpStream, download_mutex: Arc<Mutex<Download>>) -> Result<(), Error> {
-    PeerConnection::accept(stream, download_mutex)
+pub fn accept(stream: TcpStream, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) -> Result<(), Error> {
+    PeerConnection::accept(stream, download_mutex, peer_pool, choker_mutex)
 }
 
 pub struct PeerConnection {
     halt: bool,
     download_mutex: Arc<Mutex<Download>>,
     stream: TcpStream,
+    peer: PeerIdentity, // the id is filled in by the handshake
     me: PeerMetadata,
     them: PeerMetadata,
     incoming_tx: Sender<IPC>,
     outgoing_tx: Sender<Message>,
     upload_in_progress: bool,
     to_request: HashMap<(u32, u32), (u32, u32, u32)>,
+    timed_out: HashMap<(u32, u32), (u32, u32, u32, Instant)>,
+    peer_pool: Arc<Mutex<PeerPool>>,
+    choker_mutex: Arc<Mutex<Choker>>,
+    choker_id: usize,
+    listen_addr: Option<SocketAddr>,
+    last_tick: Instant,
+    last_pex: Option<Instant>,
+    pex_advertised: HashSet<SocketAddr>,
+    pipeline: Pipeline,
 }
 
 impl PeerConnection {
-    fn connect(peer: &Peer, download_mutex: Arc<Mutex<Download>>) -> Result<(), Error> {
-        println!("Connecting to {}:{}", peer.ip, peer.port);
-        let stream = try!(TcpStream::connect((peer.ip, peer.port)));
-        PeerConnection::new(stream, download_mutex, true)
+    fn connect(peer: &Peer, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) -> Result<(), Error> {
+        println!("Connecting to {}", peer.addr());
+        let stream = try!(TcpStream::connect_timeout(&peer.addr(), Duration::from_secs(CONNECT_TIMEOUT_SECS)));
+        PeerConnection::new(stream, download_mutex, peer_pool, choker_mutex, Some(peer.addr()))
     }
 
-    fn accept(stream: TcpStream, download_mutex: Arc<Mutex<Download>>) -> Result<(), Error> {
+    fn accept(stream: TcpStream, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>) -> Result<(), Error> {
         println!("Received connection from a peer!");
-        PeerConnection::new(stream, download_mutex, false)
+        PeerConnection::new(stream, download_mutex, peer_pool, choker_mutex, None)
     }
 
-    fn new(stream: TcpStream, download_mutex: Arc<Mutex<Download>>, send_handshake_first: bool) -> Result<(), Error> {
+    // listen_addr is the address we connected to, or None if they connected to us
+    fn new(stream: TcpStream, download_mutex: Arc<Mutex<Download>>, peer_pool: Arc<Mutex<PeerPool>>, choker_mutex: Arc<Mutex<Choker>>, listen_addr: Option<SocketAddr>) -> Result<(), Error> {
+        let send_handshake_first = listen_addr.is_some();
+        let peer_ip = try!(stream.peer_addr()).ip();
         let have_pieces = {
             let download = download_mutex.lock().unwrap();
             download.have_pieces()
@@ -59,9 +98,15 @@ impl PeerConnection {
         let (incoming_tx, incoming_rx) = channel::<IPC>();
         {
             let mut download = download_mutex.lock().unwrap();
-            download.register_peer(incoming_tx.clone());
+            download.register_channel(incoming_tx.clone());
         }
 
+        // register with the choker, which decides when we upload to this peer
+        let choker_id = {
+            let mut choker = choker_mutex.lock().unwrap();
+            choker.register(incoming_tx.clone())
+        };
+
         // create outgoing Message channel
         let (outgoing_tx, outgoing_rx) = channel::<Message>();
 
@@ -69,21 +114,38 @@ impl PeerConnection {
             halt: false,
             download_mutex: download_mutex,
             stream: stream,
+            peer: PeerIdentity { ip: peer_ip, id: vec![] },
             me: PeerMetadata::new(have_pieces),
             them: PeerMetadata::new(vec![false; num_pieces]),
             incoming_tx: incoming_tx,
             outgoing_tx: outgoing_tx,
             upload_in_progress: false,
             to_request: HashMap::new(),
+            timed_out: HashMap::new(),
+            peer_pool: peer_pool,
+            choker_mutex: choker_mutex,
+            choker_id: choker_id,
+            listen_addr: listen_addr,
+            last_tick: Instant::now(),
+            last_pex: None,
+            pex_advertised: HashSet::new(),
+            pipeline: Pipeline::new(),
         };
 
-        try!(conn.run(send_handshake_first, incoming_rx, outgoing_rx));
+        let choker_mutex = conn.choker_mutex.clone();
+        let result = conn.run(send_handshake_first, incoming_rx, outgoing_rx);
+        {
+            let mut choker = choker_mutex.lock().unwrap();
+            choker.unregister(choker_id);
+        }
+        try!(result);
 
         println!("Disconnected");
         Ok(())
     }
 
     fn run(mut self, send_handshake_first: bool, incoming_rx: Receiver<IPC>, outgoing_rx: Receiver<Message>) -> Result<(), Error> {
+        try!(self.stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))));
         if send_handshake_first {
             try!(self.send_handshake());
             try!(self.receive_handshake());
@@ -91,46 +153,203 @@ impl PeerConnection {
             try!(self.receive_handshake());
             try!(self.send_handshake());
         }
+        try!(self.stream.set_read_timeout(None));
 
         println!("Handshake complete");
 
+        let (upload_limiters, download_limiters) = {
+            let download = self.download_mutex.lock().unwrap();
+            let upload: Vec<Arc<Mutex<RateLimiter>>> = download.rate_limits.iter().map(|l| l.upload.clone()).collect();
+            let download: Vec<Arc<Mutex<RateLimiter>>> = download.rate_limits.iter().map(|l| l.download.clone()).collect();
+            (upload, download)
+        };
+
         // spawn a thread to funnel incoming messages from the socket into the incoming message channel
         let downstream_funnel_thread = {
             let stream = self.stream.try_clone().unwrap();
             let tx = self.incoming_tx.clone();
-            thread::spawn(move || DownstreamMessageFunnel::start(stream, tx))
+            let max_message_size = cmp::max(MAX_MESSAGE_SIZE, 1 + (self.me.has_pieces.len() as u32 + 7) / 8);
+            thread::spawn(move || DownstreamMessageFunnel::start(stream, tx, download_limiters, max_message_size))
         };
 
         // spawn a thread to funnel outgoing messages from the outgoing message channel into the socket
         let upstream_funnel_thread = {
             let stream = self.stream.try_clone().unwrap();
             let tx = self.incoming_tx.clone();
-            thread::spawn(move || UpstreamMessageFunnel::start(stream, outgoing_rx, tx))
+            thread::spawn(move || UpstreamMessageFunnel::start(stream, outgoing_rx, tx, upload_limiters))
         };
 
         // send a bitfield message letting peer know what we have
         try!(self.send_bitfield());
 
+        // if we both support the extension protocol, tell them which extensions we support (BEP 10)
+        if self.them.supports_extensions {
+            try!(self.send_extended_handshake());
+        }
+
+        // let other peers know about this one
+        if let Some(addr) = self.listen_addr {
+            let mut peer_pool = self.peer_pool.lock().unwrap();
+            peer_pool.connected(addr);
+        }
+
         // process messages received on the channel (both from the remote peer, and from Downlad)
-        while !self.halt {
-            let message = try!(incoming_rx.recv());
-            try!(self.process(message));
+        let result = self.process_incoming(&incoming_rx);
+
+        // peers we connected to are forgotten by whoever asked us to connect, once the connection is over (whether or
+        // not it got this far). we only have to forget the ones that connected to us
+        if let (false, Some(addr)) = (send_handshake_first, self.listen_addr) {
+            let mut peer_pool = self.peer_pool.lock().unwrap();
+            peer_pool.disconnected(&addr);
+        }
+
+        // the pieces this peer has, and the blocks we requested from it, are no longer available
+        {
+            let mut download = self.download_mutex.lock().unwrap();
+            download.peer_lost_pieces(&self.them.has_pieces);
+            for r in self.me.requests.remove_all() {
+                download.block_unrequested(r.piece_index, r.block_index);
+            }
+            download.release_claims(&self.peer);
         }
+        try!(result);
 
         println!("Disconnecting");
         try!(self.stream.shutdown(Shutdown::Both));
+
+        // the upstream funnel only stops waiting for messages once the channel is closed
+        drop(self.outgoing_tx);
         try!(downstream_funnel_thread.join());
         try!(upstream_funnel_thread.join());
         Ok(())
     }
 
+    fn process_incoming(&mut self, incoming_rx: &Receiver<IPC>) -> Result<(), Error> {
+        let tick_interval = Duration::from_secs(TICK_INTERVAL_SECS);
+        while !self.halt {
+            match incoming_rx.recv_timeout(tick_interval) {
+                Ok(message) => try!(self.process(message)),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::ReceiveError(RecvError))
+            }
+
+            // do periodic work, even if messages are arriving faster than the tick interval
+            if self.last_tick.elapsed() >= tick_interval {
+                self.last_tick = Instant::now();
+                try!(self.tick());
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), Error> {
+        self.pipeline.measure();
+        try!(self.time_out_requests());
+
+        // there may be blocks worth requesting now that weren't before (e.g. once the download reaches endgame mode)
+        try!(self.request_more_blocks());
+
+        let pex_due = match self.last_pex {
+            Some(t) => t.elapsed() >= Duration::from_secs(pex::INTERVAL_SECS),
+            None => true
+        };
+        if pex_due {
+            try!(self.send_pex());
+        }
+        Ok(())
+    }
+
+    fn time_out_requests(&mut self) -> Result<(), Error> {
+        let waiting = self.me.requests.len() > 0;
+        if self.pipeline.check_snubbed(waiting) {
+            println!("Peer is snubbing us");
+        }
+
+        try!(self.retry_timed_out());
+
+        let timed_out = self.me.requests.remove_older_than(Duration::from_secs(REQUEST_TIMEOUT_SECS));
+        if timed_out.len() == 0 {
+            return Ok(());
+        }
+        println!("{} requests timed out", timed_out.len());
+
+        // make the blocks available to other peers again, and only try this one again if nobody else has them by the
+        // time the cooldown is over
+        {
+            let mut download = self.download_mutex.lock().unwrap();
+            for r in timed_out.iter() {
+                download.block_unrequested(r.piece_index, r.block_index);
+                self.timed_out.insert((r.piece_index, r.block_index), (r.piece_index, r.block_index, r.block_length, Instant::now()));
+            }
+            download.release_claims(&self.peer);
+        }
+        for r in timed_out {
+            try!(self.send_message(Message::Cancel(r.piece_index, r.offset, r.block_length)));
+        }
+        try!(self.update_my_interested_status());
+        Ok(())
+    }
+
+    fn retry_timed_out(&mut self) -> Result<(), Error> {
+        let cooldown = Duration::from_secs(TIMED_OUT_COOLDOWN_SECS);
+        let retry: Vec<(u32, u32)> = self.timed_out.iter().filter(|&(_, &(_, _, _, at))| at.elapsed() >= cooldown).map(|(&k, _)| k).collect();
+        if retry.len() == 0 {
+            return Ok(());
+        }
+
+        {
+            let download = self.download_mutex.lock().unwrap();
+            for key in retry.into_iter() {
+                let (piece_index, block_index, block_length, _) = self.timed_out.remove(&key).unwrap();
+                if download.incomplete_blocks_for_piece(piece_index).iter().any(|&(b, _)| b == block_index) {
+                    self.to_request.insert(key, (piece_index, block_index, block_length));
+                }
+            }
+        }
+        self.update_my_interested_status()
+    }
+
+    // tell the peer about the peers we've connected to, and dropped, since the last time
+    fn send_pex(&mut self) -> Result<(), Error> {
+        let their_id = match self.them.extended_handshake.as_ref().and_then(|h| h.id_for("ut_pex")) {
+            Some(id) => id,
+            None => return Ok(())
+        };
+
+        let mut current = {
+            let peer_pool = self.peer_pool.lock().unwrap();
+            peer_pool.connected_peers()
+        };
+        if let Some(addr) = self.listen_addr {
+            current.remove(&addr);
+        }
+
+        let added: Vec<SocketAddr> = current.difference(&self.pex_advertised).take(pex::MAX_PEERS_PER_MESSAGE).cloned().collect();
+        let dropped: Vec<SocketAddr> = self.pex_advertised.difference(&current).take(pex::MAX_PEERS_PER_MESSAGE).cloned().collect();
+        self.last_pex = Some(Instant::now());
+        if added.len() == 0 && dropped.len() == 0 {
+            return Ok(());
+        }
+
+        for addr in added.iter() {
+            self.pex_advertised.insert(*addr);
+        }
+        for addr in dropped.iter() {
+            self.pex_advertised.remove(addr);
+        }
+        let payload = try!(pex::message(&added, &dropped));
+        self.send_message(Message::Extended(their_id, payload))
+    }
+
     fn send_handshake(&mut self) -> Result<(), Error> {
         let message = {
             let download = self.download_mutex.lock().unwrap();
             let mut message = vec![];
             message.push(PROTOCOL.len() as u8);
             message.extend(PROTOCOL.bytes());
-            message.extend(vec![0; 8].into_iter());
+            let mut reserved = vec![0; 8];
+            reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+            message.extend(reserved.into_iter());
             message.extend(download.metainfo.info_hash.iter().cloned());
             message.extend(download.our_peer_id.bytes());
             message
@@ -142,7 +361,7 @@ impl PeerConnection {
     fn receive_handshake(&mut self) -> Result<(), Error> {
         let pstrlen = try!(read_n(&mut self.stream, 1));
         try!(read_n(&mut self.stream, pstrlen[0] as u32)); // ignore pstr
-        try!(read_n(&mut self.stream, 8)); // ignore reserved
+        let reserved = try!(read_n(&mut self.stream, 8));
         let info_hash = try!(read_n(&mut self.stream, 20));
         let peer_id = try!(read_n(&mut self.stream, 20));
 
@@ -159,11 +378,27 @@ impl PeerConnection {
             if peer_id == our_peer_id {
                 return Err(Error::ConnectingToSelf);
             }
+
+            self.peer.id = peer_id;
+            if download.is_banned(&self.peer) {
+                return Err(Error::PeerBanned(self.peer.ip));
+            }
         }
 
+        self.them.supports_extensions = reserved[extension::RESERVED_BYTE] & extension::RESERVED_BIT != 0;
         Ok(())
     }
 
+    fn send_extended_handshake(&mut self) -> Result<(), Error> {
+        let listener_port = {
+            let download = self.download_mutex.lock().unwrap();
+            download.listener_port
+        };
+        let handshake = ExtendedHandshake::new(listener_port, Some(MAX_QUEUED_UPLOADS), None);
+        let payload = try!(handshake.to_bencode().to_bytes());
+        self.send_message(Message::Extended(extension::HANDSHAKE_ID, payload))
+    }
+
     fn send_message(&mut self, message: Message) -> Result<(), Error> {
         // println!("Sending: {:?}", message);
         try!(self.outgoing_tx.send(message));
@@ -175,8 +410,17 @@ impl PeerConnection {
             IPC::Message(message) => self.process_message(message),
             IPC::BlockComplete(piece_index, block_index) => {
                 self.to_request.remove(&(piece_index, block_index));
+                self.timed_out.remove(&(piece_index, block_index));
                 match self.me.requests.remove(piece_index, block_index) {
-                    Some(r) => self.send_message(Message::Cancel(r.piece_index, r.offset, r.block_length)),
+                    Some(r) => {
+                        // another peer beat this one to the block (in endgame mode), so cancel our request for it
+                        {
+                            let mut download = self.download_mutex.lock().unwrap();
+                            download.block_unrequested(piece_index, block_index);
+                        }
+                        try!(self.send_message(Message::Cancel(r.piece_index, r.offset, r.block_length)));
+                        self.request_more_blocks()
+                    },
                     None => Ok(())
                 }
             },
@@ -186,16 +430,54 @@ impl PeerConnection {
                 try!(self.send_message(Message::Have(piece_index)));
                 Ok(())
             },
+            IPC::PieceFailed(piece_index) => {
+                if self.them.has_pieces[piece_index as usize] {
+                    self.queue_blocks(piece_index);
+                    try!(self.update_my_interested_status());
+                    try!(self.request_more_blocks());
+                }
+                Ok(())
+            },
+            IPC::PeerBanned(ip) => {
+                if ip == self.peer.ip {
+                    println!("Disconnecting from banned peer");
+                    self.halt = true;
+                }
+                Ok(())
+            },
             IPC::DownloadComplete => {
                 self.halt = true;
                 try!(self.update_my_interested_status());
                 Ok(())
             },
+            IPC::Shutdown => {
+                self.halt = true;
+                Ok(())
+            },
+            IPC::Choke => self.choke_them(),
+            IPC::Unchoke => self.unchoke_them(),
+            IPC::BlockRead(piece_index, offset, data) => {
+                match data {
+                    Some(data) if !self.them.is_choked => {
+                        {
+                            let mut choker = self.choker_mutex.lock().unwrap();
+                            choker.uploaded(self.choker_id, data.len() as u64);
+                        }
+                        self.send_message(Message::Piece(piece_index, offset, data))
+                    },
+                    _ => {
+                        // we've choked them since they asked for the block, or it couldn't be read
+                        self.upload_in_progress = false;
+                        self.upload_next_block()
+                    }
+                }
+            },
             IPC::BlockUploaded => {
                 self.upload_in_progress = false;
                 try!(self.upload_next_block());
                 Ok(())
             },
+            IPC::BlockWritten(..) | IPC::PieceVerified(..) | IPC::PieceWritten(..) => Ok(()), // these only go to the download
         }
     }
 
@@ -205,6 +487,13 @@ impl PeerConnection {
             Message::KeepAlive => {},
             Message::Choke => {
                 self.me.is_choked = true;
+
+                // the peer discards our outstanding requests when it chokes us, so they'll need to be made again
+                let mut download = self.download_mutex.lock().unwrap();
+                for r in self.me.requests.remove_all() {
+                    download.block_unrequested(r.piece_index, r.block_index);
+                    self.to_request.insert((r.piece_index, r.block_index), (r.piece_index, r.block_index, r.block_length));
+                }
             },
             Message::Unchoke => {
                 if self.me.is_choked {
@@ -214,43 +503,82 @@ impl PeerConnection {
             },
             Message::Interested => {
                 self.them.is_interested = true;
-                try!(self.unchoke_them());
+                let mut choker = self.choker_mutex.lock().unwrap();
+                choker.interested(self.choker_id, true);
             },
             Message::NotInterested => {
                 self.them.is_interested = false;
+                let mut choker = self.choker_mutex.lock().unwrap();
+                choker.interested(self.choker_id, false);
             },
             Message::Have(have_index) => {
+                if have_index as usize >= self.them.has_pieces.len() {
+                    return Err(Error::InvalidMessage(4, 4));
+                }
+                if !self.them.has_pieces[have_index as usize] {
+                    let mut download = self.download_mutex.lock().unwrap();
+                    download.peer_has_piece(have_index);
+                }
                 self.them.has_pieces[have_index as usize] = true;
                 self.queue_blocks(have_index);
                 try!(self.update_my_interested_status());
                 try!(self.request_more_blocks());
             },
             Message::Bitfield(bytes) => {
+                let num_pieces = self.them.has_pieces.len();
+                if bytes.len() < (num_pieces + 7) / 8 {
+                    return Err(Error::InvalidMessage(5, bytes.len()));
+                }
+                let has_pieces: Vec<bool> = (0..num_pieces).map(|have_index| {
+                    let byte = bytes[have_index / 8];
+                    let mask = 1 << (7 - have_index % 8);
+                    (byte & mask) != 0
+                }).collect();
+                {
+                    let mut download = self.download_mutex.lock().unwrap();
+                    download.peer_lost_pieces(&self.them.has_pieces);
+                    download.peer_has_pieces(&has_pieces);
+                }
+                self.them.has_pieces = has_pieces;
+
                 for have_index in 0..self.them.has_pieces.len() {
-                    let bytes_index = have_index / 8;
-                    let index_into_byte = have_index % 8;
-                    let byte = bytes[bytes_index];
-                    let mask = 1 << (7 - index_into_byte);
-                    let value = (byte & mask) != 0;
-                    self.them.has_pieces[have_index] = value;
-                    if value {
+                    if self.them.has_pieces[have_index] {
                         self.queue_blocks(have_index as u32);
                     }
-                };
+                }
                 try!(self.update_my_interested_status());
                 try!(self.request_more_blocks());
             },
             Message::Request(piece_index, offset, length) => {
-                let block_index = offset / BLOCK_SIZE;
-                self.them.requests.add(piece_index, block_index, offset, length);
+                if piece_index as usize >= self.them.has_pieces.len() {
+                    return Err(Error::InvalidMessage(6, 12));
+                }
+                // ignore requests beyond the queue size we advertised (we don't support the fast extension, so there's
+                // no way to reject them)
+                if self.them.requests.len() < MAX_QUEUED_UPLOADS as usize {
+                    let block_index = offset / BLOCK_SIZE;
+                    self.them.requests.add(piece_index, block_index, offset, length);
+                } else {
+                    println!("Ignoring request for piece {} at offset {}, since {} are already queued", piece_index, offset, MAX_QUEUED_UPLOADS);
+                }
                 try!(self.upload_next_block());
             },
             Message::Piece(piece_index, offset, data) => {
+                if piece_index as usize >= self.them.has_pieces.len() {
+                    return Err(Error::InvalidMessage(7, 8 + data.len()));
+                }
                 let block_index = offset / BLOCK_SIZE;
-                self.me.requests.remove(piece_index, block_index);
+                {
+                    let mut choker = self.choker_mutex.lock().unwrap();
+                    choker.downloaded(self.choker_id, data.len() as u64);
+                }
                 {
                     let mut download = self.download_mutex.lock().unwrap();
-                    try!(download.store(piece_index, block_index, data))
+                    if let Some(r) = self.me.requests.remove(piece_index, block_index) {
+                        self.pipeline.received(data.len() as u64, r.requested_at.elapsed());
+                        download.block_unrequested(piece_index, block_index);
+                    }
+                    try!(download.store(piece_index, block_index, data, &self.peer))
                 }
                 try!(self.update_my_interested_status());
                 try!(self.request_more_blocks());
@@ -259,11 +587,58 @@ impl PeerConnection {
                 let block_index = offset / BLOCK_SIZE;
                 self.them.requests.remove(piece_index, block_index);
             },
+            Message::Extended(id, payload) => {
+                try!(self.process_extended_message(id, payload));
+            },
             _ => return Err(Error::UnknownRequestType(message))
         };
         Ok(())
     }
 
+    fn process_extended_message(&mut self, id: u8, payload: Vec<u8>) -> Result<(), Error> {
+        match id {
+            extension::HANDSHAKE_ID => {
+                let handshake = try!(ExtendedHandshake::parse(&payload));
+                println!("Peer is running {}", handshake.client.as_ref().map_or("an unknown client", |c| c.as_ref()));
+
+                // now we know where to find peers that connected to us
+                if let (None, Some(port)) = (self.listen_addr, handshake.port) {
+                    let addr = SocketAddr::new(try!(self.stream.peer_addr()).ip(), port);
+                    let mut peer_pool = self.peer_pool.lock().unwrap();
+                    peer_pool.connected(addr);
+                    self.listen_addr = Some(addr);
+                }
+
+                if let Some(reqq) = handshake.reqq {
+                    self.pipeline.set_max_depth(reqq);
+                }
+                self.them.extended_handshake = Some(handshake);
+            },
+            extension::UT_PEX_ID => {
+                let peers = try!(pex::parse_added(&payload));
+                let added = {
+                    let mut peer_pool = self.peer_pool.lock().unwrap();
+                    peer_pool.add(peers)
+                };
+                if added > 0 {
+                    println!("Found {} peers via peer exchange", added);
+                }
+            },
+            extension::UT_METADATA_ID => {
+                // we don't keep the raw info dictionary around, so reject any requests for it
+                let their_id = self.them.extended_handshake.as_ref().and_then(|h| h.id_for("ut_metadata"));
+                if let Some(their_id) = their_id {
+                    if let Some(piece) = ut_metadata::requested_piece(&payload) {
+                        let reject = try!(ut_metadata::reject_message(piece));
+                        try!(self.send_message(Message::Extended(their_id, reject)));
+                    }
+                }
+            },
+            _ => {}
+        };
+        Ok(())
+    }
+
     fn queue_blocks(&mut self, piece_index: u32) {
         let incomplete_blocks = {
             let download = self.download_mutex.lock().unwrap();
@@ -271,7 +646,7 @@ impl PeerConnection {
         };
 
         for (block_index, block_length) in incomplete_blocks {
-            if !self.me.requests.has(piece_index, block_index) {
+            if !self.me.requests.has(piece_index, block_index) && !self.timed_out.contains_key(&(piece_index, block_index)) {
                 self.to_request.insert((piece_index, block_index), (piece_index, block_index, block_length));
             }
         }
@@ -307,22 +682,21 @@ impl PeerConnection {
             return Ok(())
         }
 
-        while self.me.requests.len() < MAX_CONCURRENT_REQUESTS as usize {
-            let len = self.to_request.len();
-            if len == 0 {
-                return Ok(());
-            }
+        // keep enough requests outstanding to make full use of the connection
+        while self.me.requests.len() < self.pipeline.depth() {
+            let mut download = self.download_mutex.lock().unwrap();
 
-            // remove a block at random from to_request
-            let (piece_index, block_index, block_length) = {
-                let index = rand::thread_rng().gen_range(0, len);
-                let target = self.to_request.keys().nth(index).unwrap().clone();
-                self.to_request.remove(&target).unwrap()
+            // remove the best block to request next from to_request
+            let (piece_index, block_index, block_length) = match download.pick_block(&self.to_request, &self.peer) {
+                Some(target) => self.to_request.remove(&target).unwrap(),
+                None => return Ok(())
             };
 
             // add a request
             let offset = block_index * BLOCK_SIZE;
             if self.me.requests.add(piece_index, block_index, offset, block_length) {
+                download.block_requested(piece_index, block_index, &self.peer);
+                drop(download);
                 try!(self.send_message(Message::Request(piece_index, offset, block_length)));
             }
         }
@@ -330,6 +704,16 @@ impl PeerConnection {
         Ok(())
     }
 
+    fn choke_them(&mut self) -> Result<(), Error> {
+        if !self.them.is_choked {
+            // choking discards any requests they've made
+            self.them.is_choked = true;
+            self.them.requests.remove_all();
+            try!(self.send_message(Message::Choke));
+        }
+        Ok(())
+    }
+
     fn unchoke_them(&mut self) -> Result<(), Error> {
         if self.them.is_choked {
             self.them.is_choked = false;
@@ -344,14 +728,13 @@ impl PeerConnection {
             return Ok(());
         }
 
+        // the block is sent once it has been read from disk
         match self.them.requests.pop() {
             Some(r) => {
-                let data = {
-                    let mut download = self.download_mutex.lock().unwrap();
-                    try!(download.retrive_data(&r))
-                };
+                let mut download = self.download_mutex.lock().unwrap();
+                try!(download.read_block(&r, self.incoming_tx.clone()));
                 self.upload_in_progress = true;
-                self.send_message(Message::Piece(r.piece_index, r.offset, data))
+                Ok(())
             },
             None => Ok(())
         }
@@ -363,6 +746,8 @@ struct PeerMetadata {
     is_choked: bool,
     is_interested: bool,
     requests: RequestQueue,
+    supports_extensions: bool,
+    extended_handshake: Option<ExtendedHandshake>,
 }
 
 impl PeerMetadata {
@@ -372,6 +757,8 @@ impl PeerMetadata {
             is_choked: true,
             is_interested: false,
             requests: RequestQueue::new(),
+            supports_extensions: false,
+            extended_handshake: None,
         }
     }
 }
@@ -379,13 +766,17 @@ impl PeerMetadata {
 struct DownstreamMessageFunnel {
     stream: TcpStream,
     tx: Sender<IPC>,
+    limiters: Vec<Arc<Mutex<RateLimiter>>>,
+    max_message_size: u32,
 }
 
 impl DownstreamMessageFunnel {
-    fn start(stream: TcpStream, tx: Sender<IPC>) {
+    fn start(stream: TcpStream, tx: Sender<IPC>, limiters: Vec<Arc<Mutex<RateLimiter>>>, max_message_size: u32) {
         let mut funnel = DownstreamMessageFunnel {
             stream: stream,
             tx: tx,
+            limiters: limiters,
+            max_message_size: max_message_size,
         };
         match funnel.run() {
             Ok(_) => {},
@@ -402,9 +793,23 @@ impl DownstreamMessageFunnel {
 
     fn receive_message(&mut self) -> Result<Message, Error> {
         let message_size = bytes_to_u32(&try!(read_n(&mut self.stream, 4)));
+        if message_size > self.max_message_size {
+            return Err(Error::MessageTooLarge(message_size));
+        }
         if message_size > 0 {
-            let message = try!(read_n(&mut self.stream, message_size));
-            Ok(Message::new(&message[0], &message[1..]))
+            // leaving the data in the socket makes the peer slow down. only what has actually arrived counts, since
+            // the peer could claim any length and then not send it
+            let mut message = vec![0; message_size as usize];
+            let mut received = 0;
+            while received < message.len() {
+                let n = try!(self.stream.read(&mut message[received..]));
+                if n == 0 {
+                    return Err(Error::SocketClosed);
+                }
+                rate_limiter::throttle(&self.limiters, n as u64);
+                received += n;
+            }
+            Message::new(&message[0], &message[1..])
         } else {
             Ok(Message::KeepAlive)
         }
@@ -415,14 +820,16 @@ struct UpstreamMessageFunnel {
     stream: TcpStream,
     rx: Receiver<Message>,
     tx: Sender<IPC>,
+    limiters: Vec<Arc<Mutex<RateLimiter>>>,
 }
 
 impl UpstreamMessageFunnel {
-    fn start(stream: TcpStream, rx: Receiver<Message>, tx: Sender<IPC>) {
+    fn start(stream: TcpStream, rx: Receiver<Message>, tx: Sender<IPC>, limiters: Vec<Arc<Mutex<RateLimiter>>>) {
         let mut funnel = UpstreamMessageFunnel {
             stream: stream,
             rx: rx,
             tx: tx,
+            limiters: limiters,
         };
         match funnel.run() {
             Ok(_) => {},
@@ -438,8 +845,10 @@ impl UpstreamMessageFunnel {
                 _ => false
             };
 
-            // do a blocking write to the TCP stream
-            try!(self.stream.write_all(&message.serialize()));
+            // do a blocking write to the TCP stream, once the rate limits allow it
+            let bytes = message.serialize();
+            rate_limiter::throttle(&self.limiters, bytes.len() as u64);
+            try!(self.stream.write_all(&bytes));
 
             // notify the main PeerConnection thread that this block is finished
             if is_block_upload {
@@ -449,7 +858,7 @@ impl UpstreamMessageFunnel {
     }
 }
 
-fn read_n(stream: &mut TcpStream, bytes_to_read: u32) -> Result<Vec<u8>, Error> {
+pub fn read_n(stream: &mut TcpStream, bytes_to_read: u32) -> Result<Vec<u8>, Error> {
     let mut buf = vec![];
     try!(read_n_to_buf(stream, &mut buf, bytes_to_read));
     Ok(buf)
@@ -481,11 +890,24 @@ pub enum Message {
     Piece(u32, u32, Vec<u8>),
     Cancel(u32, u32, u32),
     Port, // TODO Add params
+    Extended(u8, Vec<u8>),
 }
 
 impl Message {
-    fn new(id: &u8, body: &[u8]) -> Message {
-        match *id {
+    // the body comes straight from the peer, so it's checked to be long enough for the message type
+    fn new(id: &u8, body: &[u8]) -> Result<Message, Error> {
+        let min_length = match *id {
+            4 => 4,
+            6 | 8 => 12,
+            7 => 8,
+            20 => 1,
+            _ => 0
+        };
+        if body.len() < min_length {
+            return Err(Error::InvalidMessage(*id, body.len()));
+        }
+
+        let message = match *id {
             0 => Message::Choke,
             1 => Message::Unchoke,
             2 => Message::Interested,
@@ -511,8 +933,10 @@ impl Message {
                 Message::Cancel(index, offset, length)
             },
             9 => Message::Port,
-            _ => panic!("Bad message id: {}", id)
-        }
+            20 => Message::Extended(body[0], body[1..].to_owned()),
+            _ => return Err(Error::InvalidMessage(*id, body.len()))
+        };
+        Ok(message)
     }
 
     fn serialize(self) -> Vec<u8> {
@@ -538,7 +962,7 @@ impl Message {
                 payload.extend(u32_to_bytes(length).into_iter());
             },
             Message::Piece(index, offset, data) => {
-                payload.push(6);
+                payload.push(7);
                 payload.extend(u32_to_bytes(index).into_iter());
                 payload.extend(u32_to_bytes(offset).into_iter());
                 payload.extend(data);
@@ -550,6 +974,11 @@ impl Message {
                 payload.extend(u32_to_bytes(length).into_iter());
             },
             Message::Port => payload.push(9),
+            Message::Extended(id, data) => {
+                payload.push(20);
+                payload.push(id);
+                payload.extend(data);
+            },
         };
 
         // prepend size
@@ -573,6 +1002,7 @@ impl fmt::Debug for Message {
              Message::Piece(ref index, ref offset, ref data) => write!(f, "Piece({}, {}, size={})", index, offset, data.len()),
              Message::Cancel(ref index, ref offset, ref length) => write!(f, "Cancel({}, {}, {})", index, offset, length),
              Message::Port => write!(f, "Port"),
+             Message::Extended(ref id, ref data) => write!(f, "Extended({}, size={})", id, data.len()),
         }
     }
 }
@@ -582,14 +1012,14 @@ const BYTE_1: u32 = 256 * 256;
 const BYTE_2: u32 = 256;
 const BYTE_3: u32 = 1;
 
-fn bytes_to_u32(bytes: &[u8]) -> u32 {
+pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
     bytes[0] as u32 * BYTE_0 +
     bytes[1] as u32 * BYTE_1 +
     bytes[2] as u32 * BYTE_2 +
     bytes[3] as u32 * BYTE_3
 }
 
-fn u32_to_bytes(integer: u32) -> Vec<u8> {
+pub fn u32_to_bytes(integer: u32) -> Vec<u8> {
     let mut rest = integer;
     let first = rest / BYTE_0;
     rest -= first * BYTE_0;
@@ -605,9 +1035,13 @@ fn u32_to_bytes(integer: u32) -> Vec<u8> {
 pub enum Error {
     InvalidInfoHash,
     ConnectingToSelf,
+    PeerBanned(IpAddr),
+    DecoderError(decoder::Error),
     DownloadError(download::Error),
     IoError(io::Error),
     SocketClosed,
+    InvalidMessage(u8, usize),
+    MessageTooLarge(u32),
     UnknownRequestType(Message),
     ReceiveError(RecvError),
     SendMessageError(SendError<Message>),
@@ -615,6 +1049,12 @@ pub enum Error {
     Any(Box<any::Any + Send>),
 }
 
+impl convert::From<decoder::Error> for Error {
+    fn from(err: decoder::Error) -> Error {
+        Error::DecoderError(err)
+    }
+}
+
 impl convert::From<download::Error> for Error {
     fn from(err: download::Error) -> Error {
         Error::DownloadError(err)
@@ -650,3 +1090,34 @@ impl convert::From<Box<any::Any + Send>> for Error {
         Error::Any(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_messages() {
+        match Message::new(&6, &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0]) {
+            Ok(Message::Request(1, 16384, 16384)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        match Message::new(&20, &[0, b'd', b'e']) {
+            Ok(Message::Extended(0, ref payload)) if payload == b"de" => {},
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reject_short_messages() {
+        assert!(Message::new(&20, &[]).is_err());
+        assert!(Message::new(&4, &[0, 0]).is_err());
+        assert!(Message::new(&6, &[0; 11]).is_err());
+        assert!(Message::new(&7, &[0; 7]).is_err());
+        assert!(Message::new(&8, &[]).is_err());
+    }
+
+    #[test]
+    fn reject_unknown_messages() {
+        assert!(Message::new(&42, &[]).is_err());
+    }
+}
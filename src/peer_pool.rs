@@ -0,0 +1,89 @@
+use std::collections::{HashSet, VecDeque};
+use std::usize;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+
+use tracker_response::Peer;
+
+// how many peers we connect to at once. the rest wait their turn
+const MAX_CONNECTIONS: usize = 50;
+
+// the set of peers we know about, from every source (trackers, peer exchange, etc)
+pub struct PeerPool {
+    tx: Sender<Peer>,
+    known: HashSet<SocketAddr>,
+    connected: HashSet<SocketAddr>,
+    waiting: VecDeque<Peer>,
+    connecting: HashSet<SocketAddr>,
+    max_connections: usize,
+}
+
+impl PeerPool {
+    pub fn new(tx: Sender<Peer>) -> PeerPool {
+        PeerPool::with_max_connections(tx, MAX_CONNECTIONS)
+    }
+
+    // a pool that passes along every peer straight away, for when there's no way of telling it that a peer is done with
+    pub fn unlimited(tx: Sender<Peer>) -> PeerPool {
+        PeerPool::with_max_connections(tx, usize::MAX)
+    }
+
+    fn with_max_connections(tx: Sender<Peer>, max_connections: usize) -> PeerPool {
+        PeerPool {
+            tx: tx,
+            known: HashSet::new(),
+            connected: HashSet::new(),
+            waiting: VecDeque::new(),
+            connecting: HashSet::new(),
+            max_connections: max_connections,
+        }
+    }
+
+    // pass along any peers we aren't already connecting or connected to, as long as there's room for more
+    // connections. returns how many of them were new to us
+    pub fn add(&mut self, peers: Vec<Peer>) -> usize {
+        let mut added = 0;
+        for peer in peers.into_iter() {
+            if self.known.insert(peer.addr()) {
+                self.waiting.push_back(peer);
+                added += 1;
+            }
+        }
+        self.dispatch();
+        added
+    }
+
+    // record the listen address of a peer we're connected to, so it can be shared with other peers
+    pub fn connected(&mut self, addr: SocketAddr) {
+        self.known.insert(addr);
+        self.connected.insert(addr);
+    }
+
+    // forget about a peer, so that we can connect to it again if we hear about it later. if it was one of the peers we
+    // passed along, the next waiting peer takes its place
+    pub fn disconnected(&mut self, addr: &SocketAddr) {
+        self.known.remove(addr);
+        self.connected.remove(addr);
+        if self.connecting.remove(addr) {
+            self.dispatch();
+        }
+    }
+
+    pub fn connected_peers(&self) -> HashSet<SocketAddr> {
+        self.connected.clone()
+    }
+
+    fn dispatch(&mut self) {
+        while self.connecting.len() < self.max_connections {
+            let peer = match self.waiting.pop_front() {
+                Some(peer) => peer,
+                None => return
+            };
+            let addr = peer.addr();
+            match self.tx.send(peer) {
+                Ok(_) => { self.connecting.insert(addr); },
+                Err(e) => println!("Error: {:?}", e)
+            }
+        }
+    }
+}
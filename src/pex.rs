@@ -0,0 +1,65 @@
+use bencode::Bencode;
+use bencode::util::ByteString;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use decoder;
+use tracker_response::Peer;
+
+// peer exchange messages are sent at most once a minute, with at most 50 added and 50 dropped peers (BEP 11)
+pub const INTERVAL_SECS: u64 = 60;
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+pub fn message(added: &[SocketAddr], dropped: &[SocketAddr]) -> Result<Vec<u8>, io::Error> {
+    let (added, added6) = compact(added);
+    let (dropped, dropped6) = compact(dropped);
+
+    let mut m = BTreeMap::new();
+    m.insert(ByteString::from_str("added.f"), Bencode::ByteString(vec![0; added.len() / 6]));
+    m.insert(ByteString::from_str("added"), Bencode::ByteString(added));
+    m.insert(ByteString::from_str("dropped"), Bencode::ByteString(dropped));
+    m.insert(ByteString::from_str("added6.f"), Bencode::ByteString(vec![0; added6.len() / 18]));
+    m.insert(ByteString::from_str("added6"), Bencode::ByteString(added6));
+    m.insert(ByteString::from_str("dropped6"), Bencode::ByteString(dropped6));
+    Bencode::Dict(m).to_bytes()
+}
+
+// returns the peers that were added, ignoring dropped peers since they may still be reachable
+pub fn parse_added(payload: &[u8]) -> Result<Vec<Peer>, decoder::Error> {
+    match try!(decoder::parse_untrusted(payload)) {
+        Bencode::Dict(ref m) => {
+            let mut peers = vec![];
+            if let Some(&Bencode::ByteString(ref added)) = m.get(&ByteString::from_str("added")) {
+                peers.extend(added.chunks(6).filter(|c| c.len() == 6).map(Peer::from_bytes));
+            }
+            if let Some(&Bencode::ByteString(ref added6)) = m.get(&ByteString::from_str("added6")) {
+                peers.extend(added6.chunks(18).filter(|c| c.len() == 18).map(Peer::from_bytes_v6));
+            }
+            Ok(peers)
+        },
+        _ => Err(decoder::Error::NotADict)
+    }
+}
+
+// split addresses into compact IPv4 and IPv6 strings
+fn compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for addr in addrs.iter() {
+        let port = addr.port();
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend(ip.octets().iter().cloned());
+                v4.push((port >> 8) as u8);
+                v4.push(port as u8);
+            },
+            IpAddr::V6(ip) => {
+                v6.extend(ip.octets().iter().cloned());
+                v6.push((port >> 8) as u8);
+                v6.push(port as u8);
+            }
+        }
+    }
+    (v4, v6)
+}
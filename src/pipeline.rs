@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use download::BLOCK_SIZE;
+
+// how many requests we keep outstanding with a peer before we know anything about it, and the bounds after that
+const INITIAL_QUEUE_DEPTH: usize = 10;
+const MIN_QUEUE_DEPTH: usize = 4;
+const MAX_QUEUE_DEPTH: usize = 500;
+
+// keep twice the bandwidth-delay product in flight, so the queue can grow when the link allows more
+const BDP_MULTIPLIER: f64 = 2.0;
+
+// the round-trip time is the quickest response seen recently, since slower ones were waiting behind our other requests
+const RTT_WINDOW_SECS: u64 = 30;
+
+// how much each throughput measurement counts towards the average
+const THROUGHPUT_WEIGHT: f64 = 0.5;
+
+// a peer that hasn't sent us a block in this long, despite our requests, is snubbing us, and only gets one at a time
+const SNUB_TIMEOUT_SECS: u64 = 60;
+const SNUBBED_QUEUE_DEPTH: usize = 1;
+
+// sizes the queue of outstanding requests to a peer, from its throughput and round-trip time
+pub struct Pipeline {
+    max_depth: usize,
+    throughput: Option<f64>,
+    rtt: Option<Duration>,
+    rtt_measured_at: Instant,
+    bytes_received: u64,
+    measured_at: Instant,
+    received_at: Instant,
+    waiting_since: Option<Instant>,
+    is_snubbed: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline {
+            max_depth: MAX_QUEUE_DEPTH,
+            throughput: None,
+            rtt: None,
+            rtt_measured_at: Instant::now(),
+            bytes_received: 0,
+            measured_at: Instant::now(),
+            received_at: Instant::now(),
+            waiting_since: None,
+            is_snubbed: false,
+        }
+    }
+
+    // never queue more requests than the peer told us it can handle (its reqq)
+    pub fn set_max_depth(&mut self, reqq: u32) {
+        self.max_depth = (reqq as usize).max(1).min(MAX_QUEUE_DEPTH);
+    }
+
+    // record a block arriving, `latency` after we requested it
+    pub fn received(&mut self, bytes: u64, latency: Duration) {
+        self.bytes_received += bytes;
+        self.received_at = Instant::now();
+        self.is_snubbed = false;
+
+        let window_expired = self.rtt_measured_at.elapsed() >= Duration::from_secs(RTT_WINDOW_SECS);
+        if window_expired || self.rtt.map_or(true, |rtt| latency < rtt) {
+            self.rtt = Some(latency);
+            self.rtt_measured_at = Instant::now();
+        }
+    }
+
+    // update the throughput, from the bytes received since the last time this was called
+    pub fn measure(&mut self) {
+        let elapsed = self.measured_at.elapsed();
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        if elapsed_secs <= 0.0 {
+            return;
+        }
+        let sample = self.bytes_received as f64 / elapsed_secs;
+        self.throughput = Some(match self.throughput {
+            Some(t) => t * (1.0 - THROUGHPUT_WEIGHT) + sample * THROUGHPUT_WEIGHT,
+            None => sample
+        });
+        self.bytes_received = 0;
+        self.measured_at = Instant::now();
+    }
+
+    // decide whether the peer is snubbing us, given whether we're waiting on any requests to it. returns true if it
+    // has only just started
+    pub fn check_snubbed(&mut self, waiting: bool) -> bool {
+        if !waiting {
+            self.waiting_since = None;
+            return false;
+        }
+        let waiting_since = *self.waiting_since.get_or_insert(Instant::now());
+
+        let timeout = Duration::from_secs(SNUB_TIMEOUT_SECS);
+        let newly_snubbed = !self.is_snubbed && waiting_since.elapsed() >= timeout && self.received_at.elapsed() >= timeout;
+        if newly_snubbed {
+            self.is_snubbed = true;
+        }
+        newly_snubbed
+    }
+
+    // how many requests to keep outstanding
+    pub fn depth(&self) -> usize {
+        if self.is_snubbed {
+            return SNUBBED_QUEUE_DEPTH;
+        }
+
+        let depth = match (self.throughput, self.rtt) {
+            (Some(throughput), Some(rtt)) => {
+                let rtt_secs = rtt.as_secs() as f64 + rtt.subsec_nanos() as f64 / 1e9;
+                let bdp = throughput * rtt_secs;
+                ((bdp * BDP_MULTIPLIER / BLOCK_SIZE as f64).ceil() as usize).max(MIN_QUEUE_DEPTH)
+            },
+            _ => INITIAL_QUEUE_DEPTH
+        };
+        depth.min(self.max_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(throughput: f64, rtt_millis: u64) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        pipeline.throughput = Some(throughput);
+        pipeline.rtt = Some(Duration::from_millis(rtt_millis));
+        pipeline
+    }
+
+    #[test]
+    fn depth_follows_bandwidth_delay_product() {
+        assert_eq!(Pipeline::new().depth(), INITIAL_QUEUE_DEPTH);
+
+        // 1 MiB/s with a 100ms round trip is 6.4 blocks in flight, doubled
+        assert_eq!(pipeline(1024.0 * 1024.0, 100).depth(), 13);
+        assert_eq!(pipeline(1024.0 * 1024.0, 200).depth(), 26);
+
+        assert_eq!(pipeline(1024.0, 100).depth(), MIN_QUEUE_DEPTH);
+        assert_eq!(pipeline(1024.0 * 1024.0 * 1024.0, 100).depth(), MAX_QUEUE_DEPTH);
+
+        let mut limited = pipeline(1024.0 * 1024.0 * 1024.0, 100);
+        limited.set_max_depth(20);
+        assert_eq!(limited.depth(), 20);
+
+        let mut snubbed = pipeline(1024.0 * 1024.0, 100);
+        snubbed.is_snubbed = true;
+        assert_eq!(snubbed.depth(), SNUBBED_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn rtt_is_the_quickest_response() {
+        let mut pipeline = Pipeline::new();
+        pipeline.received(BLOCK_SIZE as u64, Duration::from_millis(200));
+        pipeline.received(BLOCK_SIZE as u64, Duration::from_millis(100));
+        pipeline.received(BLOCK_SIZE as u64, Duration::from_millis(300));
+        assert_eq!(pipeline.rtt, Some(Duration::from_millis(100)));
+    }
+}
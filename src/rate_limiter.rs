@@ -0,0 +1,144 @@
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// a token bucket, which lets through up to `rate` bytes per second, with bursts of up to a second's worth
+pub struct RateLimiter {
+    rate: Option<u64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    // a rate of None means unlimited
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            rate: rate,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+        self.tokens = 0.0;
+        self.refilled_at = Instant::now();
+    }
+
+    // take tokens for the given number of bytes, and return how long to wait before sending them. the bucket can go
+    // into debt, which makes whoever comes next wait their turn
+    fn take(&mut self, bytes: u64) -> Duration {
+        let elapsed = self.refilled_at.elapsed();
+        self.refilled_at = Instant::now();
+
+        match self.rate {
+            Some(rate) => {
+                let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                self.tokens = (self.tokens + elapsed_secs * rate as f64).min(rate as f64);
+                self.tokens -= bytes as f64;
+                if self.tokens >= 0.0 {
+                    Duration::from_secs(0)
+                } else {
+                    Duration::from_millis((-self.tokens / rate as f64 * 1000.0) as u64)
+                }
+            },
+            None => Duration::from_secs(0)
+        }
+    }
+}
+
+// the upload and download limiters for one scope (e.g. the whole client, or a single torrent)
+#[derive(Clone)]
+pub struct RateLimits {
+    pub upload: Arc<Mutex<RateLimiter>>,
+    pub download: Arc<Mutex<RateLimiter>>,
+}
+
+impl RateLimits {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> RateLimits {
+        RateLimits {
+            upload: Arc::new(Mutex::new(RateLimiter::new(upload))),
+            download: Arc::new(Mutex::new(RateLimiter::new(download))),
+        }
+    }
+}
+
+// block until every one of the limiters lets the bytes through
+pub fn throttle(limiters: &[Arc<Mutex<RateLimiter>>], bytes: u64) {
+    for limiter in limiters.iter() {
+        let wait = {
+            let mut limiter = limiter.lock().unwrap();
+            limiter.take(bytes)
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// rates are given in KiB/s, where 0 means unlimited
+pub fn parse_rate(s: &str) -> Result<Option<u64>, Error> {
+    let kib: u64 = try!(s.parse().map_err(|_| Error::NotANumber));
+    match kib.checked_mul(1024) {
+        Some(0) => Ok(None),
+        Some(rate) => Ok(Some(rate)),
+        None => Err(Error::TooLarge)
+    }
+}
+
+pub fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(r) => format!("{} KiB/s", r / 1024),
+        None => "unlimited".to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotANumber,
+    TooLarge,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert_eq!(parse_rate("128").unwrap(), Some(128 * 1024));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("18014398509481984").is_err());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_a_second() {
+        let mut limiter = RateLimiter::new(Some(1000));
+
+        // a long wait only saves up a second's worth
+        limiter.refilled_at = Instant::now() - Duration::from_secs(5);
+        assert_eq!(limiter.take(0), Duration::from_secs(0));
+        assert!(limiter.tokens <= 1000.0);
+        assert_eq!(limiter.take(1000), Duration::from_secs(0));
+
+        // and going over makes the next bytes wait for it to refill
+        let wait = limiter.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        limiter.tokens = 0.0;
+        limiter.refilled_at = Instant::now() - Duration::from_millis(250);
+        limiter.take(0);
+        assert!(limiter.tokens >= 250.0 && limiter.tokens < 300.0);
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let mut limiter = RateLimiter::new(None);
+        assert_eq!(limiter.take(1 << 30), Duration::from_secs(0));
+    }
+}
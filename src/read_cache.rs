@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+
+// the most recently read pieces, kept in memory so popular pieces can be uploaded to many peers without going back to
+// the disk for each one. when it's full, the least recently used piece makes way
+pub struct ReadCache {
+    capacity: u64,
+    size: u64,
+    pieces: HashMap<u32, CachedPiece>,
+    loading: HashSet<u32>, // pieces being read from disk, to be inserted
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedPiece {
+    data: Vec<u8>,
+    used_at: u64,
+}
+
+pub struct ReadCacheStats {
+    pub capacity: u64,
+    pub size: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ReadCache {
+    // a capacity of 0 turns the cache off
+    pub fn new(capacity: u64) -> ReadCache {
+        ReadCache {
+            capacity: capacity,
+            size: 0,
+            pieces: HashMap::new(),
+            loading: HashSet::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    // whether a piece this long can be cached at all
+    pub fn fits(&self, length: u64) -> bool {
+        length <= self.capacity
+    }
+
+    // claims a piece that missed, for the caller to read from disk and insert. returns false if it's already being
+    // read, in which case the caller should only read what it needs
+    pub fn start_loading(&mut self, piece_index: u32) -> bool {
+        self.loading.insert(piece_index)
+    }
+
+    // gives up a claim from start_loading without inserting the piece, e.g. because reading it failed
+    pub fn cancel_loading(&mut self, piece_index: u32) {
+        self.loading.remove(&piece_index);
+    }
+
+    // returns the requested part of the piece, if it's cached
+    pub fn get(&mut self, piece_index: u32, offset: u32, length: u32) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let start = offset as usize;
+        let end = start + length as usize;
+        match self.pieces.get_mut(&piece_index) {
+            Some(piece) if end <= piece.data.len() => {
+                piece.used_at = self.clock;
+                self.hits += 1;
+                Some(piece.data[start..end].to_vec())
+            },
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, piece_index: u32, data: Vec<u8>) {
+        self.loading.remove(&piece_index);
+        let length = data.len() as u64;
+        if length > self.capacity {
+            return;
+        }
+        self.remove(piece_index);
+        while self.size + length > self.capacity {
+            self.evict();
+        }
+
+        self.clock += 1;
+        self.size += length;
+        self.pieces.insert(piece_index, CachedPiece { data: data, used_at: self.clock });
+    }
+
+    pub fn remove(&mut self, piece_index: u32) {
+        if let Some(piece) = self.pieces.remove(&piece_index) {
+            self.size -= piece.data.len() as u64;
+        }
+    }
+
+    pub fn stats(&self) -> ReadCacheStats {
+        ReadCacheStats {
+            capacity: self.capacity,
+            size: self.size,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    fn evict(&mut self) {
+        let oldest = self.pieces.iter().min_by_key(|&(_, p)| p.used_at).map(|(&i, _)| i);
+        if let Some(piece_index) = oldest {
+            self.remove(piece_index);
+        }
+    }
+}
+
+impl ReadCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 { 0.0 } else { self.hits as f64 / total as f64 * 100.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ReadCache::new(8);
+        cache.insert(0, vec![0; 4]);
+        cache.insert(1, vec![1; 4]);
+        assert_eq!(cache.get(0, 0, 2), Some(vec![0, 0]));
+
+        // piece 1 hasn't been used since it was inserted, so it makes way
+        cache.insert(2, vec![2; 4]);
+        assert_eq!(cache.get(1, 0, 4), None);
+        assert_eq!(cache.get(0, 2, 2), Some(vec![0, 0]));
+        assert_eq!(cache.get(2, 0, 4), Some(vec![2; 4]));
+        assert_eq!(cache.stats().size, 8);
+
+        // too big to cache at all
+        cache.insert(3, vec![3; 9]);
+        assert_eq!(cache.get(3, 0, 1), None);
+        assert_eq!(cache.stats().size, 8);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = ReadCache::new(8);
+        assert_eq!(cache.get(0, 0, 4), None);
+        cache.insert(0, vec![0; 4]);
+        assert!(cache.get(0, 0, 4).is_some());
+        assert!(cache.get(0, 2, 2).is_some());
+        assert_eq!(cache.get(0, 2, 4), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.hit_rate(), 50.0);
+    }
+
+    #[test]
+    fn one_loader_per_piece() {
+        let mut cache = ReadCache::new(8);
+        assert!(cache.start_loading(0));
+        assert!(!cache.start_loading(0));
+        cache.insert(0, vec![0; 4]);
+        assert!(cache.start_loading(0));
+        cache.cancel_loading(0);
+        assert!(cache.start_loading(0));
+    }
+}
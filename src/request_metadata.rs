@@ -1,9 +1,12 @@
+use std::time::Instant;
+
 #[derive(Debug)]
 pub struct RequestMetadata {
     pub piece_index: u32,
     pub block_index: u32,
     pub offset: u32,
     pub block_length: u32,
+    pub requested_at: Instant,
 }
 
 impl RequestMetadata {
@@ -1,3 +1,5 @@
+use std::time::{Duration, Instant};
+
 use request_metadata::RequestMetadata;
 
 #[derive(Debug)]
@@ -21,6 +23,7 @@ impl RequestQueue {
                 block_index: block_index,
                 offset: offset,
                 block_length: block_length,
+                requested_at: Instant::now(),
             };
             self.requests.push(r);
             true
@@ -37,6 +40,16 @@ impl RequestQueue {
         }
     }
 
+    pub fn remove_older_than(&mut self, age: Duration) -> Vec<RequestMetadata> {
+        let (old, new) = self.requests.drain(..).partition(|r| r.requested_at.elapsed() >= age);
+        self.requests = new;
+        old
+    }
+
+    pub fn remove_all(&mut self) -> Vec<RequestMetadata> {
+        self.requests.drain(..).collect()
+    }
+
     pub fn remove(&mut self, piece_index: u32, block_index: u32) -> Option<RequestMetadata> {
         match self.position(piece_index, block_index) {
             Some(i) => {
@@ -0,0 +1,151 @@
+use bencode;
+use bencode::{Bencode, FromBencode};
+use bencode::util::ByteString;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use decoder;
+
+// what we knew about a download when we last stopped, so we don't have to hash everything again on startup
+pub struct Resume {
+    pub info_hash: Vec<u8>,
+    pub pieces: Vec<bool>,
+    pub partial_pieces: Vec<PartialPiece>,
+    pub files: Vec<FileStat>,
+    pub is_checkpoint: bool, // saved while the download was still running
+}
+
+// the blocks we have of a piece that isn't complete yet
+pub struct PartialPiece {
+    pub index: u32,
+    pub blocks: Vec<bool>,
+}
+
+// the size and modification time of a file on disk. if either has changed, the resume data can't be trusted
+#[derive(PartialEq, Debug)]
+pub struct FileStat {
+    pub length: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+}
+
+impl Resume {
+    // returns None if there's no resume file
+    pub fn load(path: &Path) -> Result<Option<Resume>, decoder::Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut f = try!(File::open(path));
+        let mut bytes = Vec::new();
+        try!(f.read_to_end(&mut bytes));
+        let resume = try!(Resume::parse(&bytes));
+        Ok(Some(resume))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), decoder::Error> {
+        let mut partial_pieces = vec![];
+        for p in self.partial_pieces.iter() {
+            let mut m = BTreeMap::new();
+            m.insert(ByteString::from_str("index"), Bencode::Number(p.index as i64));
+            m.insert(ByteString::from_str("blocks"), Bencode::ByteString(to_bitfield(&p.blocks)));
+            partial_pieces.push(Bencode::Dict(m));
+        }
+
+        let mut files = vec![];
+        for f in self.files.iter() {
+            let mut m = BTreeMap::new();
+            m.insert(ByteString::from_str("length"), Bencode::Number(f.length as i64));
+            m.insert(ByteString::from_str("mtime"), Bencode::Number(f.mtime_secs as i64));
+            m.insert(ByteString::from_str("mtime nanos"), Bencode::Number(f.mtime_nanos as i64));
+            files.push(Bencode::Dict(m));
+        }
+
+        let mut m = BTreeMap::new();
+        m.insert(ByteString::from_str("info hash"), Bencode::ByteString(self.info_hash.clone()));
+        m.insert(ByteString::from_str("num pieces"), Bencode::Number(self.pieces.len() as i64));
+        m.insert(ByteString::from_str("pieces"), Bencode::ByteString(to_bitfield(&self.pieces)));
+        m.insert(ByteString::from_str("partial pieces"), Bencode::List(partial_pieces));
+        m.insert(ByteString::from_str("files"), Bencode::List(files));
+        m.insert(ByteString::from_str("checkpoint"), Bencode::Number(if self.is_checkpoint { 1 } else { 0 }));
+        let bytes = try!(Bencode::Dict(m).to_bytes());
+
+        let mut f = try!(File::create(path));
+        try!(f.write_all(&bytes));
+        Ok(())
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Resume, decoder::Error> {
+        match try!(bencode::from_buffer(bytes)) {
+            Bencode::Dict(ref m) => {
+                let num_pieces: u32 = get_field!(m, "num pieces");
+
+                let mut partial_pieces = vec![];
+                for p in get_field_as_list!(m, "partial pieces").iter() {
+                    partial_pieces.push(try!(parse_partial_piece(p)));
+                }
+
+                let mut files = vec![];
+                for f in get_field_as_list!(m, "files").iter() {
+                    files.push(try!(parse_file_stat(f)));
+                }
+
+                let resume = Resume {
+                    info_hash: get_field_as_bytes!(m, "info hash"),
+                    pieces: from_bitfield(&get_field_as_bytes!(m, "pieces"), num_pieces as usize),
+                    partial_pieces: partial_pieces,
+                    files: files,
+                    is_checkpoint: match m.get(&ByteString::from_str("checkpoint")) {
+                        Some(&Bencode::Number(n)) => n != 0,
+                        _ => false
+                    },
+                };
+                Ok(resume)
+            },
+            _ => Err(decoder::Error::NotADict)
+        }
+    }
+}
+
+// the number of blocks isn't saved, so the bitfield may be padded with a few extra false values
+fn parse_partial_piece(bencode: &Bencode) -> Result<PartialPiece, decoder::Error> {
+    match bencode {
+        &Bencode::Dict(ref m) => {
+            let blocks = get_field_as_bytes!(m, "blocks");
+            let num_blocks = blocks.len() * 8;
+            Ok(PartialPiece {
+                index: get_field!(m, "index"),
+                blocks: from_bitfield(&blocks, num_blocks),
+            })
+        },
+        _ => Err(decoder::Error::NotADict)
+    }
+}
+
+fn parse_file_stat(bencode: &Bencode) -> Result<FileStat, decoder::Error> {
+    match bencode {
+        &Bencode::Dict(ref m) => {
+            Ok(FileStat {
+                length: get_field!(m, "length"),
+                mtime_secs: get_field!(m, "mtime"),
+                mtime_nanos: get_field!(m, "mtime nanos"),
+            })
+        },
+        _ => Err(decoder::Error::NotADict)
+    }
+}
+
+fn to_bitfield(values: &[bool]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0; (values.len() + 7) / 8];
+    for (i, &value) in values.iter().enumerate() {
+        if value {
+            bytes[i / 8] |= 1 << (7 - i % 8);
+        }
+    }
+    bytes
+}
+
+fn from_bitfield(bytes: &[u8], len: usize) -> Vec<bool> {
+    (0..len).map(|i| i / 8 < bytes.len() && bytes[i / 8] & (1 << (7 - i % 8)) != 0).collect()
+}
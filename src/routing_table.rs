@@ -0,0 +1,180 @@
+use rand;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use krpc::{NodeId, NodeInfo, NODE_ID_LENGTH};
+
+// each bucket holds up to K nodes
+pub const K: usize = 8;
+
+// a node we haven't heard from in 15 minutes is questionable, and one that has failed to answer a few queries is bad
+const QUESTIONABLE_SECS: u64 = 15 * 60;
+const MAX_FAILURES: u32 = 2;
+
+// buckets that haven't changed in 15 minutes are refreshed by looking up a random id in their range
+const REFRESH_SECS: u64 = 15 * 60;
+
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+// a Kademlia routing table (BEP 5), with one bucket for each bit of distance from our own id
+pub struct RoutingTable {
+    our_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+    changed_at: Vec<Instant>,
+}
+
+impl RoutingTable {
+    pub fn new(our_id: NodeId) -> RoutingTable {
+        let mut buckets = vec![];
+        let mut changed_at = vec![];
+        for _ in 0..(NODE_ID_LENGTH * 8) {
+            buckets.push(vec![]);
+            changed_at.push(Instant::now());
+        }
+        RoutingTable {
+            our_id: our_id,
+            buckets: buckets,
+            changed_at: changed_at,
+        }
+    }
+
+    pub fn our_id(&self) -> &NodeId {
+        &self.our_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    // record that a node is alive, adding it to the table if there's room for it
+    pub fn heard_from(&mut self, node: NodeInfo) {
+        let index = match self.bucket_index(&node.id) {
+            Some(i) => i,
+            None => return
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            self.changed_at[index] = Instant::now();
+            return;
+        }
+
+        let entry = Entry { node: node, last_seen: Instant::now(), failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+            self.changed_at[index] = Instant::now();
+            return;
+        }
+
+        // a full bucket only makes room by evicting a node that has gone quiet
+        if let Some(i) = bucket.iter().position(|e| e.is_stale()) {
+            bucket[i] = entry;
+            self.changed_at[index] = Instant::now();
+        }
+    }
+
+    // whether heard_from would add a node we haven't met yet, so it's worth pinging
+    pub fn has_room_for(&self, id: &[u8]) -> bool {
+        match self.bucket_index(id) {
+            Some(i) => {
+                let bucket = &self.buckets[i];
+                !bucket.iter().any(|e| e.node.id == id) && (bucket.len() < K || bucket.iter().any(|e| e.is_stale()))
+            },
+            None => false
+        }
+    }
+
+    // record that a node didn't answer, removing it once it has gone bad
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            for entry in bucket.iter_mut().filter(|e| e.node.addr == *addr) {
+                entry.failures += 1;
+            }
+            bucket.retain(|e| e.failures < MAX_FAILURES);
+        }
+    }
+
+    // nodes we haven't heard from in a while, which should be pinged to see if they're still there
+    pub fn questionable(&self) -> Vec<NodeInfo> {
+        let questionable = Duration::from_secs(QUESTIONABLE_SECS);
+        self.buckets.iter()
+            .flat_map(|b| b.iter().filter(|e| e.last_seen.elapsed() >= questionable).map(|e| e.node.clone()))
+            .collect()
+    }
+
+    // a random id to look up for each bucket that hasn't changed in a while, marking them as refreshed. buckets past
+    // the closest one we have nodes in are left alone, since there's usually nobody that close to us
+    pub fn refresh_targets(&mut self) -> Vec<NodeId> {
+        let deepest = match self.buckets.iter().rposition(|b| b.len() > 0) {
+            Some(i) => i,
+            None => return vec![]
+        };
+
+        let refresh = Duration::from_secs(REFRESH_SECS);
+        let mut targets = vec![];
+        for index in 0..(deepest + 1) {
+            if self.changed_at[index].elapsed() >= refresh {
+                self.changed_at[index] = Instant::now();
+                targets.push(self.random_id_in(index));
+            }
+        }
+        targets
+    }
+
+    pub fn closest(&self, target: &[u8], count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.sort_by(|a, b| distance(&a.id, target).cmp(&distance(&b.id, target)));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flat_map(|b| b.iter().map(|e| e.node.clone())).collect()
+    }
+
+    // an id sharing exactly `index` leading bits with ours
+    fn random_id_in(&self, index: usize) -> NodeId {
+        let mut rng = rand::thread_rng();
+        let mut id = self.our_id.clone();
+        let (byte, bit) = (index / 8, 7 - index % 8);
+        id[byte] ^= 1 << bit;
+        let random: u8 = rng.gen();
+        let mask = ((1u16 << bit) - 1) as u8;
+        id[byte] = (id[byte] & !mask) | (random & mask);
+        for b in id[(byte + 1)..].iter_mut() {
+            *b = rng.gen();
+        }
+        id
+    }
+
+    // the bucket for an id is the number of leading bits it shares with ours
+    fn bucket_index(&self, id: &[u8]) -> Option<usize> {
+        if id.len() != NODE_ID_LENGTH {
+            return None;
+        }
+        let d = distance(&self.our_id, id);
+        match d.iter().position(|&b| b != 0) {
+            Some(i) => Some(i * 8 + d[i].leading_zeros() as usize),
+            None => None // that's us
+        }
+    }
+}
+
+impl Entry {
+    fn is_stale(&self) -> bool {
+        self.failures > 0 || self.last_seen.elapsed() >= Duration::from_secs(QUESTIONABLE_SECS)
+    }
+}
+
+// the XOR metric, which compares as a big-endian number
+pub fn distance(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
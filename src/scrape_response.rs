@@ -0,0 +1,63 @@
+use bencode;
+use bencode::{Bencode, FromBencode};
+use bencode::util::ByteString;
+use std::collections::HashMap;
+
+use decoder;
+use hash::Sha1;
+
+#[derive(PartialEq, Debug)]
+pub struct ScrapeResponse {
+    pub files: HashMap<Sha1, ScrapeStats>,
+}
+
+impl FromBencode for ScrapeResponse {
+    type Err = decoder::Error;
+
+    fn from_bencode(bencode: &bencode::Bencode) -> Result<ScrapeResponse, decoder::Error> {
+        match bencode {
+            &Bencode::Dict(ref m) => {
+                // the files dictionary is keyed by the raw 20-byte info hash
+                let files = match get_raw_field!(m, "files") {
+                    &Bencode::Dict(ref f) => {
+                        let mut files = HashMap::new();
+                        for (info_hash, stats) in f.iter() {
+                            let stats = try!(FromBencode::from_bencode(stats));
+                            files.insert(info_hash.as_slice().to_owned(), stats);
+                        }
+                        files
+                    },
+                    _ => return Err(decoder::Error::NotADict)
+                };
+
+                Ok(ScrapeResponse { files: files })
+            }
+            _ => Err(decoder::Error::NotADict)
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ScrapeStats {
+    pub complete: u32,
+    pub downloaded: u32,
+    pub incomplete: u32,
+}
+
+impl FromBencode for ScrapeStats {
+    type Err = decoder::Error;
+
+    fn from_bencode(bencode: &bencode::Bencode) -> Result<ScrapeStats, decoder::Error> {
+        match bencode {
+            &Bencode::Dict(ref m) => {
+                let stats = ScrapeStats {
+                    complete: get_field!(m, "complete"),
+                    downloaded: get_field_with_default!(m, "downloaded", 0),
+                    incomplete: get_field!(m, "incomplete"),
+                };
+                Ok(stats)
+            }
+            _ => Err(decoder::Error::NotADict)
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use metainfo::Info;
+use resume::FileStat;
+
+pub struct Storage {
+    files: Vec<StorageFile>,
+}
+
+struct StorageFile {
+    offset: u64,
+    length: u64,
+    file:   File,
+}
+
+impl Storage {
+    pub fn new(directory: &Path, info: &Info) -> Result<Storage, io::Error> {
+        let mut files = vec![];
+        let mut offset = 0;
+        for file_info in info.files.iter() {
+            // create/open file, along with any directories leading up to it
+            let path = directory.join(&file_info.path);
+            if let Some(parent) = path.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            let file = try!(OpenOptions::new().create(true).read(true).write(true).open(path));
+
+            files.push(StorageFile {
+                offset: offset,
+                length: file_info.length,
+                file:   file,
+            });
+            offset += file_info.length;
+        }
+
+        Ok(Storage { files: files })
+    }
+
+    // the size and modification time of each file, as it is on disk right now
+    pub fn file_stats(&self) -> Result<Vec<FileStat>, io::Error> {
+        let mut stats = vec![];
+        for f in self.files.iter() {
+            let metadata = try!(f.file.metadata());
+            let mtime = match try!(metadata.modified()).duration_since(UNIX_EPOCH) {
+                Ok(d) => d,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "modification time is before 1970"))
+            };
+            stats.push(FileStat {
+                length: metadata.len(),
+                mtime_secs: mtime.as_secs(),
+                mtime_nanos: mtime.subsec_nanos(),
+            });
+        }
+        Ok(stats)
+    }
+
+    pub fn read(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, io::Error> {
+        let mut buf = vec![];
+        for (file_index, file_offset, span_length) in self.spans(offset, length) {
+            let file = &mut self.files[file_index].file;
+            try!(file.seek(io::SeekFrom::Start(file_offset)));
+            let bytes_read = try!(file.take(span_length).read_to_end(&mut buf));
+            if (bytes_read as u64) < span_length {
+                // the file hasn't been fully written yet, so there's nothing further to read
+                break;
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let mut data_offset = 0;
+        for (file_index, file_offset, span_length) in self.spans(offset, data.len() as u64) {
+            let file = &mut self.files[file_index].file;
+            try!(file.seek(io::SeekFrom::Start(file_offset)));
+            try!(file.write_all(&data[data_offset..(data_offset + span_length as usize)]));
+            data_offset += span_length as usize;
+        }
+        Ok(())
+    }
+
+    // split a range of the torrent into (file index, offset into file, length) spans
+    fn spans(&self, offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = offset + length;
+        let mut spans = vec![];
+        for (i, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.length == 0 {
+                continue;
+            }
+            if file.offset >= end {
+                break;
+            }
+            let start = if offset > file.offset { offset } else { file.offset };
+            let stop = if end < file_end { end } else { file_end };
+            spans.push((i, start - file.offset, stop - start));
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::path::PathBuf;
+
+    use metainfo::FileInfo;
+
+    // a (5 bytes), an empty file, b (7 bytes) and c (3 bytes), in 4 byte pieces, so the last piece is 3 bytes
+    fn storage(name: &str) -> (Storage, PathBuf) {
+        let directory = env::temp_dir().join(format!("rusty_torrent_test_{}_{}", name, process::id()));
+        let files = vec![("a", 5), ("empty", 0), ("b", 7), ("c", 3)];
+        let info = Info {
+            piece_length: 4,
+            pieces: vec![],
+            num_pieces: 4,
+            name: "test".to_string(),
+            length: 15,
+            files: files.into_iter().map(|(path, length)| FileInfo { length: length, path: PathBuf::from(path) }).collect(),
+        };
+        (Storage::new(&directory, &info).unwrap(), directory)
+    }
+
+    #[test]
+    fn spans() {
+        let (storage, directory) = storage("spans");
+
+        // across the end of b and into c
+        assert_eq!(storage.spans(10, 4), vec![(2, 5, 2), (3, 0, 2)]);
+
+        // across the empty file, which has nothing to read or write
+        assert_eq!(storage.spans(4, 4), vec![(0, 4, 1), (2, 0, 3)]);
+
+        // the last piece, which is short
+        assert_eq!(storage.spans(12, 3), vec![(3, 0, 3)]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn write_and_read_across_files() {
+        let (mut storage, directory) = storage("read_write");
+        let data: Vec<u8> = (0..15).collect();
+        storage.write(0, &data[0..4]).unwrap();
+        storage.write(4, &data[4..8]).unwrap();
+        storage.write(8, &data[8..12]).unwrap();
+        storage.write(12, &data[12..15]).unwrap();
+
+        assert_eq!(storage.read(4, 4).unwrap(), vec![4, 5, 6, 7]);
+        assert_eq!(storage.read(0, 15).unwrap(), data);
+
+        let mut a = vec![];
+        File::open(directory.join("a")).unwrap().read_to_end(&mut a).unwrap();
+        assert_eq!(a, vec![0, 1, 2, 3, 4]);
+        assert_eq!(fs::metadata(directory.join("empty")).unwrap().len(), 0);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,38 +1,254 @@
 extern crate hyper;
 extern crate url;
 
-use std::{convert, io};
+use bencode::{Bencode, FromBencode};
+use bencode::util::ByteString;
+use rand;
+use rand::Rng;
+use std::{cmp, convert, io};
+use std::collections::HashMap;
 use std::io::Read;
 use self::hyper::Client;
 use self::hyper::header::Connection;
 use self::url::percent_encoding::{percent_encode, FORM_URLENCODED_ENCODE_SET};
 
 use decoder;
-use metainfo::Metainfo;
+use download::Stats;
+use hash::Sha1;
+use scrape_response::ScrapeResponse;
 use tracker_response::{Peer, TrackerResponse};
+use udp_tracker::UdpTracker;
 
-pub fn get_peers(peer_id: &str, metainfo: &Metainfo, listener_port: u16) ->Result<Vec<Peer>, Error> {
-    let length_string = metainfo.info.length.to_string();
-    let encoded_info_hash = percent_encode(&metainfo.info_hash, FORM_URLENCODED_ENCODE_SET);
-    let listener_port_string = listener_port.to_string();
-    let params = vec![("left", length_string.as_ref()),
-                      ("info_hash", encoded_info_hash.as_ref()),
-                      ("downloaded", "0"),
-                      ("uploaded", "0"),
-                      ("event", "started"),
-                      ("peer_id", peer_id),
-                      ("compact", "1"),
-                      ("port", listener_port_string.as_ref())];
-    let url = format!("{}?{}", metainfo.announce, encode_query_params(&params));
+const DEFAULT_INTERVAL: u32 = 1800;
 
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+    Regular,
+}
+
+pub struct Tracker {
+    info_hash: Vec<u8>,
+    tiers: Vec<Vec<String>>,
+    interval: u32,
+    udp_trackers: HashMap<String, UdpTracker>,
+    tracker_ids: HashMap<String, String>,
+}
+
+// the parameters sent along with an announce, whichever protocol the tracker speaks
+pub struct Announce<'a> {
+    pub info_hash: &'a [u8],
+    pub peer_id: &'a str,
+    pub listener_port: u16,
+    pub stats: Stats,
+    pub event: Event,
+}
+
+impl Tracker {
+    pub fn new(info_hash: Sha1, tiers: Vec<Vec<String>>) -> Tracker {
+        // shuffle the trackers within each tier, as per BEP 12
+        let mut tiers = tiers;
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            rng.shuffle(tier);
+        }
+        Tracker {
+            info_hash: info_hash,
+            tiers: tiers,
+            interval: DEFAULT_INTERVAL,
+            udp_trackers: HashMap::new(),
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    // number of seconds to wait before the next regular announce
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn get_peers(&mut self, peer_id: &str, listener_port: u16, stats: Stats, event: Event) -> Result<Vec<Peer>, Error> {
+        let mut peers = vec![];
+        let mut last_error = Error::NoTrackers;
+        let mut interval = None;
+        let mut responded = false;
+        let announce = Announce {
+            info_hash: &self.info_hash,
+            peer_id: peer_id,
+            listener_port: listener_port,
+            stats: stats,
+            event: event,
+        };
+
+        // ask one tracker from each tier, and merge all the peers we hear about
+        for tier in self.tiers.iter_mut() {
+            match announce_to_tier(tier, &mut self.udp_trackers, &mut self.tracker_ids, &announce) {
+                Ok(res) => {
+                    responded = true;
+
+                    // re-announce as often as the most demanding tracker wants, but never more often than it allows
+                    let tier_interval = cmp::max(res.interval, res.min_interval.unwrap_or(0));
+                    interval = Some(cmp::min(interval.unwrap_or(tier_interval), tier_interval));
+                    for peer in res.peers.into_iter() {
+                        if !peers.iter().any(|p: &Peer| p.addr() == peer.addr()) {
+                            peers.push(peer);
+                        }
+                    }
+                },
+                Err(e) => last_error = e
+            }
+        }
+
+        if let Some(i) = interval {
+            self.interval = i;
+        }
+
+        if !responded {
+            Err(last_error)
+        } else {
+            Ok(peers)
+        }
+    }
+}
+
+// ask a tracker how many seeders and leechers it knows about for each of the given torrents
+pub fn scrape(announce_url: &str, info_hashes: &[Sha1]) -> Result<ScrapeResponse, Error> {
+    if announce_url.starts_with("udp://") {
+        let mut udp_tracker = try!(UdpTracker::new(announce_url));
+        udp_tracker.scrape(info_hashes)
+    } else if announce_url.starts_with("http://") || announce_url.starts_with("https://") {
+        scrape_http(announce_url, info_hashes)
+    } else {
+        Err(Error::UnsupportedUrl(announce_url.to_string()))
+    }
+}
+
+fn announce_to_tier(tier: &mut Vec<String>, udp_trackers: &mut HashMap<String, UdpTracker>, tracker_ids: &mut HashMap<String, String>, announce: &Announce) -> Result<TrackerResponse, Error> {
+    let mut last_error = Error::NoTrackers;
+    for i in 0..tier.len() {
+        match announce_to_tracker(&tier[i], udp_trackers, tracker_ids, announce) {
+            Ok(res) => {
+                // move the tracker that answered to the front of its tier
+                let url = tier.remove(i);
+                tier.insert(0, url);
+                return Ok(res);
+            },
+            Err(Error::TrackerFailure(reason)) => {
+                println!("Tracker {} failed: {}", tier[i], reason);
+                last_error = Error::TrackerFailure(reason);
+            },
+            Err(e) => {
+                println!("Tracker {} failed: {:?}", tier[i], e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn announce_to_tracker(announce_url: &str, udp_trackers: &mut HashMap<String, UdpTracker>, tracker_ids: &mut HashMap<String, String>, announce: &Announce) -> Result<TrackerResponse, Error> {
+    if announce_url.starts_with("udp://") {
+        // keep UDP tracker clients around between announces, so they can re-use their connection ids
+        if !udp_trackers.contains_key(announce_url) {
+            let udp_tracker = try!(UdpTracker::new(announce_url));
+            udp_trackers.insert(announce_url.to_string(), udp_tracker);
+        }
+        udp_trackers.get_mut(announce_url).unwrap().announce(announce)
+    } else if announce_url.starts_with("http://") || announce_url.starts_with("https://") {
+        let res = try!(announce_http(announce_url, tracker_ids.get(announce_url), announce));
+        if let Some(ref warning) = res.warning_message {
+            println!("Tracker {} warning: {}", announce_url, warning);
+        }
+
+        // trackers that give us an id expect to see it again on later announces
+        if let Some(ref tracker_id) = res.tracker_id {
+            tracker_ids.insert(announce_url.to_string(), tracker_id.clone());
+        }
+        Ok(res)
+    } else {
+        Err(Error::UnsupportedUrl(announce_url.to_string()))
+    }
+}
+
+fn announce_http(announce_url: &str, tracker_id: Option<&String>, announce: &Announce) -> Result<TrackerResponse, Error> {
+    let left_string = announce.stats.left.to_string();
+    let downloaded_string = announce.stats.downloaded.to_string();
+    let uploaded_string = announce.stats.uploaded.to_string();
+    let encoded_info_hash = percent_encode(announce.info_hash, FORM_URLENCODED_ENCODE_SET);
+    let listener_port_string = announce.listener_port.to_string();
+    let mut params = vec![("left", left_string.as_ref()),
+                          ("info_hash", encoded_info_hash.as_ref()),
+                          ("downloaded", downloaded_string.as_ref()),
+                          ("uploaded", uploaded_string.as_ref()),
+                          ("peer_id", announce.peer_id),
+                          ("compact", "1"),
+                          ("port", listener_port_string.as_ref())];
+    match announce.event {
+        Event::Started => params.push(("event", "started")),
+        Event::Completed => params.push(("event", "completed")),
+        Event::Stopped => params.push(("event", "stopped")),
+        Event::Regular => {}
+    }
+    let encoded_tracker_id = tracker_id.map(|id| percent_encode(id.as_bytes(), FORM_URLENCODED_ENCODE_SET));
+    if let Some(ref id) = encoded_tracker_id {
+        params.push(("trackerid", id));
+    }
+    let url = append_query(announce_url, &encode_query_params(&params));
+
+    let body = try!(http_get(&url));
+    parse_response(&body)
+}
+
+fn scrape_http(announce_url: &str, info_hashes: &[Sha1]) -> Result<ScrapeResponse, Error> {
+    let encoded_info_hashes: Vec<String> = info_hashes.iter().map(|h| percent_encode(h, FORM_URLENCODED_ENCODE_SET)).collect();
+    let params: Vec<(&str, &str)> = encoded_info_hashes.iter().map(|h| ("info_hash", h.as_ref())).collect();
+    let url = append_query(&try!(scrape_url(announce_url)), &encode_query_params(&params));
+
+    let body = try!(http_get(&url));
+    parse_response(&body)
+}
+
+// by convention, the scrape URL is the announce URL with the final "announce" path component replaced with "scrape"
+fn scrape_url(announce_url: &str) -> Result<String, Error> {
+    let (path, query) = match announce_url.find('?') {
+        Some(i) => announce_url.split_at(i),
+        None => (announce_url, "")
+    };
+    match path.rfind('/') {
+        Some(i) if path[(i + 1)..].starts_with("announce") => {
+            Ok(format!("{}/scrape{}{}", &path[..i], &path[(i + 1 + "announce".len())..], query))
+        },
+        _ => Err(Error::ScrapeNotSupported(announce_url.to_string()))
+    }
+}
+
+fn http_get(url: &str) -> Result<Vec<u8>, Error> {
     let mut client = Client::new();
-    let mut http_res = try!(client.get(&url).header(Connection::close()).send());
+    let mut http_res = try!(client.get(url).header(Connection::close()).send());
 
     let mut body = Vec::new();
     try!(http_res.read_to_end(&mut body));
+    Ok(body)
+}
+
+// HTTP trackers reply with a dictionary containing just a failure reason when they refuse a request
+fn parse_response<T: FromBencode<Err=decoder::Error>>(body: &[u8]) -> Result<T, Error> {
+    let bencode = try!(decoder::parse_untrusted(body));
+    if let Bencode::Dict(ref m) = bencode {
+        if m.contains_key(&ByteString::from_str("failure reason")) {
+            let reason: String = try!(FromBencode::from_bencode(&m[&ByteString::from_str("failure reason")]).map_err(decoder::Error::from));
+            return Err(Error::TrackerFailure(reason));
+        }
+    }
+    let res = try!(FromBencode::from_bencode(&bencode));
+    Ok(res)
+}
 
-    let res = try!(TrackerResponse::parse(&body));
-    Ok(res.peers)
+// tracker URLs may already have a query string (e.g. for a passkey)
+fn append_query(url: &str, query: &str) -> String {
+    let separator = if url.contains('?') { "&" } else { "?" };
+    format!("{}{}{}", url, separator, query)
 }
 
 fn encode_query_params(params: &[(&str, &str)]) -> String {
@@ -45,6 +261,12 @@ pub enum Error {
     DecoderError(decoder::Error),
     HyperError(hyper::Error),
     IoError(io::Error),
+    NoTrackers,
+    UnsupportedUrl(String),
+    ScrapeNotSupported(String),
+    InvalidResponse,
+    TrackerFailure(String),
+    Timeout,
 }
 
 impl convert::From<decoder::Error> for Error {
@@ -1,7 +1,7 @@
 use bencode;
 use bencode::{Bencode, FromBencode};
 use bencode::util::ByteString;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
 use decoder;
 
@@ -12,13 +12,8 @@ pub struct TrackerResponse {
     pub complete: u32,
     pub incomplete: u32,
     pub peers: Vec<Peer>,
-}
-
-impl TrackerResponse {
-    pub fn parse(bytes: &[u8]) -> Result<TrackerResponse, decoder::Error> {
-        let bencode = try!(bencode::from_buffer(bytes));
-        FromBencode::from_bencode(&bencode)
-    }
+    pub warning_message: Option<String>,
+    pub tracker_id: Option<String>,
 }
 
 impl FromBencode for TrackerResponse {
@@ -27,14 +22,36 @@ impl FromBencode for TrackerResponse {
     fn from_bencode(bencode: &bencode::Bencode) -> Result<TrackerResponse, decoder::Error> {
         match bencode {
             &Bencode::Dict(ref m) => {
-                let peers = get_field_as_bytes!(m, "peers").chunks(6).map(Peer::from_bytes).collect();
+                // peers are either a compact string of IPv4 addresses, or a list of dictionaries (BEP 3)
+                let mut peers = match get_raw_field!(m, "peers") {
+                    &Bencode::ByteString(ref v) => v.chunks(6).filter(|c| c.len() == 6).map(Peer::from_bytes).collect(),
+                    &Bencode::List(ref l) => {
+                        let mut peers = vec![];
+                        for p in l.iter() {
+                            match Peer::from_bencode(p) {
+                                Ok(peer) => peers.push(peer),
+                                Err(e) => println!("Ignoring peer: {:?}", e)
+                            }
+                        }
+                        peers
+                    },
+                    _ => return Err(decoder::Error::NotAByteString)
+                };
+
+                // IPv6 peers come in a separate compact string (BEP 7)
+                if m.contains_key(&ByteString::from_str("peers6")) {
+                    let peers6 = get_field_as_bytes!(m, "peers6");
+                    peers.extend(peers6.chunks(18).filter(|c| c.len() == 18).map(Peer::from_bytes_v6));
+                }
 
                 let response = TrackerResponse{
                     interval: get_field!(m, "interval"),
                     min_interval: get_optional_field!(m, "min interval"),
-                    complete: get_field!(m, "complete"),
-                    incomplete: get_field!(m, "incomplete"),
+                    complete: get_field_with_default!(m, "complete", 0),
+                    incomplete: get_field_with_default!(m, "incomplete", 0),
                     peers: peers,
+                    warning_message: get_optional_field!(m, "warning message"),
+                    tracker_id: get_optional_field!(m, "tracker id"),
                 };
                 Ok(response)
             }
@@ -43,16 +60,67 @@ impl FromBencode for TrackerResponse {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
+    pub peer_id: Option<Vec<u8>>,
 }
 
 impl Peer {
-    fn from_bytes(v: &[u8]) -> Peer {
+    pub fn new(ip: IpAddr, port: u16) -> Peer {
+        Peer { ip: ip, port: port, peer_id: None }
+    }
+
+    pub fn from_bytes(v: &[u8]) -> Peer {
         let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
         let port = (v[4] as u16) * 256 + (v[5] as u16);
-        Peer{ ip: ip, port: port }
+        Peer::new(IpAddr::V4(ip), port)
+    }
+
+    pub fn from_bytes_v6(v: &[u8]) -> Peer {
+        let mut segments = [0u16; 8];
+        for i in 0..8 {
+            segments[i] = (v[i * 2] as u16) * 256 + (v[i * 2 + 1] as u16);
+        }
+        let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]);
+        let port = (v[16] as u16) * 256 + (v[17] as u16);
+        Peer::new(IpAddr::V6(ip), port)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+impl FromBencode for Peer {
+    type Err = decoder::Error;
+
+    fn from_bencode(bencode: &bencode::Bencode) -> Result<Peer, decoder::Error> {
+        match bencode {
+            &Bencode::Dict(ref m) => {
+                let host: String = get_field!(m, "ip");
+                let port: u16 = get_field!(m, "port");
+                let peer_id = if m.contains_key(&ByteString::from_str("peer id")) {
+                    Some(get_field_as_bytes!(m, "peer id"))
+                } else {
+                    None
+                };
+
+                // the ip may be an IPv4 address, an IPv6 address, or a DNS name
+                let ip = match host.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => {
+                        match try!((host.as_ref(), port).to_socket_addrs()).next() {
+                            Some(addr) => addr.ip(),
+                            None => return Err(decoder::Error::UnresolvableHost(host))
+                        }
+                    }
+                };
+
+                Ok(Peer { ip: ip, port: port, peer_id: peer_id })
+            }
+            _ => Err(decoder::Error::NotADict)
+        }
     }
 }
@@ -0,0 +1,361 @@
+use rand;
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use hash::Sha1;
+use scrape_response::{ScrapeResponse, ScrapeStats};
+use tracker::{Announce, Error, Event};
+use tracker_response::{Peer, TrackerResponse};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// as per BEP 15, wait 15 * 2 ^ n seconds for a response. BEP 15 keeps going up to n = 8, but that's over two hours for
+// a dead tracker, so we only retry once before moving on to the next one
+const BASE_TIMEOUT_SECS: u64 = 15;
+const MAX_RETRANSMISSIONS: u32 = 1;
+
+// we can use a connection id for a minute after receiving it
+const CONNECTION_ID_LIFETIME_SECS: u64 = 60;
+
+const MAX_PACKET_SIZE: usize = 65536;
+
+pub struct UdpTracker {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    pub fn new(url: &str) -> Result<UdpTracker, Error> {
+        let addr = try!(parse_url(url));
+        let socket = match addr {
+            SocketAddr::V4(_) => try!(UdpSocket::bind("0.0.0.0:0")),
+            SocketAddr::V6(_) => try!(UdpSocket::bind("[::]:0")),
+        };
+        Ok(UdpTracker {
+            addr: addr,
+            socket: socket,
+            connection: None,
+        })
+    }
+
+    pub fn announce(&mut self, announce: &Announce) -> Result<TrackerResponse, Error> {
+        let event = match announce.event {
+            Event::Regular => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        };
+        let key: u32 = rand::thread_rng().gen();
+
+        let mut body = vec![];
+        body.extend(announce.info_hash.iter().cloned());
+        body.extend(announce.peer_id.bytes());
+        push_u64(&mut body, announce.stats.downloaded);
+        push_u64(&mut body, announce.stats.left);
+        push_u64(&mut body, announce.stats.uploaded);
+        push_u32(&mut body, event);
+        push_u32(&mut body, 0); // let the tracker use the address the packet came from
+        push_u32(&mut body, key);
+        push_u32(&mut body, -1i32 as u32); // as many peers as the tracker wants to give us
+        push_u16(&mut body, announce.listener_port);
+
+        let res = try!(self.request(ACTION_ANNOUNCE, &body));
+        if res.len() < 12 {
+            return Err(Error::InvalidResponse);
+        }
+
+        // trackers reply with IPv6 peers when we talk to them over IPv6
+        let peers = match self.addr {
+            SocketAddr::V4(_) => res[12..].chunks(6).filter(|c| c.len() == 6).map(Peer::from_bytes).collect(),
+            SocketAddr::V6(_) => res[12..].chunks(18).filter(|c| c.len() == 18).map(Peer::from_bytes_v6).collect(),
+        };
+        Ok(TrackerResponse {
+            interval: read_u32(&res[0..4]),
+            min_interval: None,
+            incomplete: read_u32(&res[4..8]),
+            complete: read_u32(&res[8..12]),
+            peers: peers,
+            warning_message: None,
+            tracker_id: None,
+        })
+    }
+
+    pub fn scrape(&mut self, info_hashes: &[Sha1]) -> Result<ScrapeResponse, Error> {
+        let mut body = vec![];
+        for info_hash in info_hashes.iter() {
+            body.extend(info_hash.iter().cloned());
+        }
+
+        let res = try!(self.request(ACTION_SCRAPE, &body));
+        if res.len() < info_hashes.len() * 12 {
+            return Err(Error::InvalidResponse);
+        }
+
+        // the stats come back in the same order as the info hashes we asked about
+        let mut files = HashMap::new();
+        for (info_hash, stats) in info_hashes.iter().zip(res.chunks(12)) {
+            files.insert(info_hash.clone(), ScrapeStats {
+                complete: read_u32(&stats[0..4]),
+                downloaded: read_u32(&stats[4..8]),
+                incomplete: read_u32(&stats[8..12]),
+            });
+        }
+        Ok(ScrapeResponse { files: files })
+    }
+
+    // send a request using a valid connection id, and return the body of the response
+    fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, Error> {
+        for n in 0..(MAX_RETRANSMISSIONS + 1) {
+            let connection_id = try!(self.connection_id());
+
+            let mut packet = vec![];
+            push_u64(&mut packet, connection_id);
+            push_u32(&mut packet, action);
+            let transaction_id = push_transaction_id(&mut packet);
+            packet.extend(body.iter().cloned());
+
+            match try!(self.send_and_receive(&packet, action, transaction_id, n)) {
+                Some(res) => return Ok(res),
+                None => {}
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn connection_id(&mut self) -> Result<u64, Error> {
+        if let Some((connection_id, received_at)) = self.connection {
+            if received_at.elapsed() < Duration::from_secs(CONNECTION_ID_LIFETIME_SECS) {
+                return Ok(connection_id);
+            }
+        }
+
+        for n in 0..(MAX_RETRANSMISSIONS + 1) {
+            let mut packet = vec![];
+            push_u64(&mut packet, PROTOCOL_ID);
+            push_u32(&mut packet, ACTION_CONNECT);
+            let transaction_id = push_transaction_id(&mut packet);
+
+            match try!(self.send_and_receive(&packet, ACTION_CONNECT, transaction_id, n)) {
+                Some(ref res) if res.len() >= 8 => {
+                    let connection_id = read_u64(&res[0..8]);
+                    self.connection = Some((connection_id, Instant::now()));
+                    return Ok(connection_id);
+                },
+                Some(_) => return Err(Error::InvalidResponse),
+                None => {}
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    // returns None if the tracker didn't answer within the timeout for attempt n
+    fn send_and_receive(&mut self, packet: &[u8], action: u32, transaction_id: u32, n: u32) -> Result<Option<Vec<u8>>, Error> {
+        let timeout = Duration::from_secs(BASE_TIMEOUT_SECS * 2u64.pow(n));
+        let deadline = Instant::now() + timeout;
+        try!(self.socket.send_to(packet, self.addr));
+
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            try!(self.socket.set_read_timeout(Some(deadline - now)));
+
+            let (size, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(Error::IoError(e))
+            };
+
+            // ignore anything that isn't a response to this request (e.g. late answers to earlier attempts)
+            if from != self.addr || size < 8 || read_u32(&buf[4..8]) != transaction_id {
+                continue;
+            }
+
+            let res_action = read_u32(&buf[0..4]);
+            if res_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&buf[8..size]).into_owned();
+                return Err(Error::TrackerFailure(message));
+            } else if res_action != action {
+                return Err(Error::InvalidResponse);
+            }
+            return Ok(Some(buf[8..size].to_owned()));
+        }
+    }
+}
+
+// extract the host and port from a URL like udp://tracker.example.com:80/announce
+fn parse_url(url: &str) -> Result<SocketAddr, Error> {
+    let rest = &url["udp://".len()..];
+    let host_and_port = match rest.find('/') {
+        Some(i) => &rest[..i],
+        None => rest
+    };
+    let mut addrs = try!(host_and_port.to_socket_addrs());
+    match addrs.next() {
+        Some(addr) => Ok(addr),
+        None => Err(Error::UnsupportedUrl(url.to_string()))
+    }
+}
+
+fn push_transaction_id(buf: &mut Vec<u8>) -> u32 {
+    let transaction_id: u32 = rand::thread_rng().gen();
+    push_u32(buf, transaction_id);
+    transaction_id
+}
+
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.push((n >> 8) as u8);
+    buf.push(n as u8);
+}
+
+fn push_u32(buf: &mut Vec<u8>, n: u32) {
+    for i in (0..4).rev() {
+        buf.push((n >> (i * 8)) as u8);
+    }
+}
+
+fn push_u64(buf: &mut Vec<u8>, n: u64) {
+    for i in (0..8).rev() {
+        buf.push((n >> (i * 8)) as u8);
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0..4].iter().fold(0, |n, &b| (n << 8) | b as u32)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    bytes[0..8].iter().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    use download::Stats;
+    use tracker::{Announce, Error, Event};
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    // a stand-in tracker on localhost, which answers `requests` packets and then stops. announces get back one peer,
+    // scrapes get back the same stats for every info hash, and an error message is sent for any other action
+    fn start_tracker(requests: usize) -> (String, JoinHandle<()>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            for _ in 0..requests {
+                let (size, from) = socket.recv_from(&mut buf).unwrap();
+                let connection_id = read_u64(&buf[0..8]);
+                let action = read_u32(&buf[8..12]);
+                let transaction_id = read_u32(&buf[12..16]);
+
+                let mut res = vec![];
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        push_u32(&mut res, ACTION_CONNECT);
+                        push_u32(&mut res, transaction_id);
+                        push_u64(&mut res, CONNECTION_ID);
+                    },
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(connection_id, CONNECTION_ID);
+                        assert_eq!(size, 98);
+                        push_u32(&mut res, ACTION_ANNOUNCE);
+                        push_u32(&mut res, transaction_id);
+                        push_u32(&mut res, 900); // interval
+                        push_u32(&mut res, 2); // leechers
+                        push_u32(&mut res, 3); // seeders
+                        res.extend([10, 0, 0, 1, 0x1a, 0xe1].iter().cloned());
+                    },
+                    ACTION_SCRAPE => {
+                        assert_eq!(connection_id, CONNECTION_ID);
+                        push_u32(&mut res, ACTION_SCRAPE);
+                        push_u32(&mut res, transaction_id);
+                        for _ in 0..((size - 16) / 20) {
+                            push_u32(&mut res, 5); // seeders
+                            push_u32(&mut res, 6); // completed
+                            push_u32(&mut res, 7); // leechers
+                        }
+                    },
+                    _ => {
+                        push_u32(&mut res, ACTION_ERROR);
+                        push_u32(&mut res, transaction_id);
+                        res.extend(b"unsupported action".iter().cloned());
+                    }
+                }
+                socket.send_to(&res, from).unwrap();
+            }
+        });
+        (url, thread)
+    }
+
+    #[test]
+    fn announce() {
+        let (url, thread) = start_tracker(2);
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        let info_hash = vec![1; 20];
+        let announce = Announce {
+            info_hash: &info_hash,
+            peer_id: "-RC0001-abcdefghijkl",
+            listener_port: 6881,
+            stats: Stats { uploaded: 0, downloaded: 0, left: 100 },
+            event: Event::Started,
+        };
+
+        let res = tracker.announce(&announce).unwrap();
+        assert_eq!(res.interval, 900);
+        assert_eq!(res.incomplete, 2);
+        assert_eq!(res.complete, 3);
+        assert_eq!(res.peers.len(), 1);
+        assert_eq!(res.peers[0].addr(), "10.0.0.1:6881".parse().unwrap());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn scrape() {
+        let (url, thread) = start_tracker(2);
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        let info_hashes = vec![vec![1; 20], vec![2; 20]];
+
+        let res = tracker.scrape(&info_hashes).unwrap();
+        assert_eq!(res.files.len(), 2);
+        let stats = &res.files[&info_hashes[1]];
+        assert_eq!((stats.complete, stats.downloaded, stats.incomplete), (5, 6, 7));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connection_id_is_reused() {
+        // one connect, then two scrapes on the same connection id
+        let (url, thread) = start_tracker(3);
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        tracker.scrape(&[vec![1; 20]]).unwrap();
+        tracker.scrape(&[vec![2; 20]]).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn error_response() {
+        let (url, thread) = start_tracker(2);
+        let mut tracker = UdpTracker::new(&url).unwrap();
+        match tracker.request(42, &[]) {
+            Err(Error::TrackerFailure(ref message)) if message == "unsupported action" => {},
+            other => panic!("expected a tracker failure, got {:?}", other.map(|r| r.len()))
+        }
+        thread.join().unwrap();
+    }
+}
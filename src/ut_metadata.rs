@@ -0,0 +1,289 @@
+use bencode::{Bencode, FromBencode, ToBencode};
+use bencode::util::ByteString;
+use std::{cmp, convert, io, thread};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use decoder;
+use extension;
+use extension::ExtendedHandshake;
+use hash::{calculate_sha1, Sha1};
+use peer_connection;
+use peer_connection::{bytes_to_u32, read_n, u32_to_bytes, PROTOCOL};
+use tracker_response::Peer;
+
+// metadata is exchanged in 16KiB pieces (BEP 9)
+pub const METADATA_PIECE_SIZE: u32 = 16384;
+
+// refuse to download anything bigger than this, since it comes from untrusted peers
+const MAX_METADATA_SIZE: u32 = 16 * 1024 * 1024;
+
+// the biggest extended message we'll read: a metadata piece, plus plenty of room for the dictionary in front of it
+const MAX_EXTENDED_MESSAGE_SIZE: u32 = METADATA_PIECE_SIZE + 16 * 1024;
+
+const EXTENDED_MESSAGE_ID: u8 = 20;
+const MSG_TYPE_REQUEST: u32 = 0;
+const MSG_TYPE_DATA: u32 = 1;
+const MSG_TYPE_REJECT: u32 = 2;
+
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+const READ_TIMEOUT_SECS: u64 = 30;
+
+// how long to keep waiting for peers that have the metadata, while there might still be more on the way
+const FETCH_TIMEOUT_SECS: u64 = 5 * 60;
+const POLL_INTERVAL_MILLIS: u64 = 250;
+
+// ask peers for the info dictionary in parallel as we hear about them, and return the first one to match the info
+// hash. gives up once all the peers have failed and the channel is closed, or after the timeout
+pub fn fetch(info_hash: &Sha1, our_peer_id: &str, listener_port: u16, peers: Receiver<Peer>) -> Result<Vec<u8>, Error> {
+    let (tx, rx) = channel();
+    let done = Arc::new(AtomicBool::new(false));
+    let deadline = Instant::now() + Duration::from_secs(FETCH_TIMEOUT_SECS);
+    let mut running = 0;
+    let mut more_peers = true;
+
+    loop {
+        while more_peers {
+            match peers.try_recv() {
+                Ok(peer) => {
+                    let tx = tx.clone();
+                    let info_hash = info_hash.clone();
+                    let our_peer_id = our_peer_id.to_string();
+                    let done = done.clone();
+                    thread::spawn(move || {
+                        let result = MetadataFetcher::connect(&peer, info_hash, &our_peer_id, listener_port, done).and_then(|mut f| f.fetch());
+                        tx.send(result).ok(); // we may have already finished
+                    });
+                    running += 1;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => more_peers = false
+            }
+        }
+
+        let now = Instant::now();
+        if (!more_peers && running == 0) || now >= deadline {
+            break;
+        }
+
+        match rx.recv_timeout(cmp::min(deadline - now, Duration::from_millis(POLL_INTERVAL_MILLIS))) {
+            Ok(Ok(info_bytes)) => {
+                // let the other fetchers know they can stop
+                done.store(true, Ordering::SeqCst);
+                return Ok(info_bytes);
+            },
+            Ok(Err(e)) => {
+                running -= 1;
+                println!("Error: {:?}", e);
+            },
+            Err(_) => {}
+        }
+    }
+
+    done.store(true, Ordering::SeqCst);
+    Err(Error::MetadataUnavailable)
+}
+
+// the piece index of a metadata request from a peer, if that's what the message is
+pub fn requested_piece(payload: &[u8]) -> Option<u32> {
+    match parse_message_header(payload) {
+        Ok((MSG_TYPE_REQUEST, piece)) => Some(piece),
+        _ => None
+    }
+}
+
+pub fn reject_message(piece: u32) -> Result<Vec<u8>, io::Error> {
+    message(MSG_TYPE_REJECT, piece)
+}
+
+fn request_message(piece: u32) -> Result<Vec<u8>, io::Error> {
+    message(MSG_TYPE_REQUEST, piece)
+}
+
+fn message(msg_type: u32, piece: u32) -> Result<Vec<u8>, io::Error> {
+    let mut m = BTreeMap::new();
+    m.insert(ByteString::from_str("msg_type"), msg_type.to_bencode());
+    m.insert(ByteString::from_str("piece"), piece.to_bencode());
+    Bencode::Dict(m).to_bytes()
+}
+
+// returns the message type and piece index from the start of a ut_metadata message
+fn parse_message_header(bytes: &[u8]) -> Result<(u32, u32), decoder::Error> {
+    match try!(decoder::parse_untrusted(bytes)) {
+        Bencode::Dict(ref m) => {
+            let msg_type = get_field!(m, "msg_type");
+            let piece = get_field!(m, "piece");
+            Ok((msg_type, piece))
+        },
+        _ => Err(decoder::Error::NotADict)
+    }
+}
+
+struct MetadataFetcher {
+    stream: TcpStream,
+    info_hash: Sha1,
+    done: Arc<AtomicBool>,
+    their_ut_metadata_id: u8,
+    metadata_size: u32,
+}
+
+impl MetadataFetcher {
+    // done is set once someone else has fetched the metadata
+    fn connect(peer: &Peer, info_hash: Sha1, our_peer_id: &str, listener_port: u16, done: Arc<AtomicBool>) -> Result<MetadataFetcher, Error> {
+        if done.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+        println!("Connecting to {} for metadata", peer.addr());
+        let mut stream = try!(TcpStream::connect_timeout(&peer.addr(), Duration::from_secs(CONNECT_TIMEOUT_SECS)));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+
+        // handshake, advertising support for the extension protocol
+        let mut reserved = vec![0; 8];
+        reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+        let mut message = vec![];
+        message.push(PROTOCOL.len() as u8);
+        message.extend(PROTOCOL.bytes());
+        message.extend(reserved.into_iter());
+        message.extend(info_hash.iter().cloned());
+        message.extend(our_peer_id.bytes());
+        try!(stream.write_all(&message));
+
+        let pstrlen = try!(read_n(&mut stream, 1));
+        try!(read_n(&mut stream, pstrlen[0] as u32)); // ignore pstr
+        let their_reserved = try!(read_n(&mut stream, 8));
+        let their_info_hash = try!(read_n(&mut stream, 20));
+        try!(read_n(&mut stream, 20)); // ignore peer id
+        if their_info_hash != info_hash {
+            return Err(Error::InvalidInfoHash);
+        }
+        if their_reserved[extension::RESERVED_BYTE] & extension::RESERVED_BIT == 0 {
+            return Err(Error::ExtensionsNotSupported);
+        }
+
+        let mut fetcher = MetadataFetcher {
+            stream: stream,
+            info_hash: info_hash,
+            done: done,
+            their_ut_metadata_id: 0,
+            metadata_size: 0,
+        };
+
+        // exchange extended handshakes, to find out if they have the metadata and how big it is
+        let our_handshake = ExtendedHandshake::new(listener_port, None, None);
+        try!(fetcher.send_extended(extension::HANDSHAKE_ID, &try!(our_handshake.to_bencode().to_bytes())));
+        let their_handshake = try!(ExtendedHandshake::parse(&try!(fetcher.receive_extended(extension::HANDSHAKE_ID))));
+        match (their_handshake.id_for("ut_metadata"), their_handshake.metadata_size) {
+            (Some(id), Some(size)) if size > 0 && size <= MAX_METADATA_SIZE => {
+                fetcher.their_ut_metadata_id = id;
+                fetcher.metadata_size = size;
+            },
+            _ => return Err(Error::MetadataNotSupported)
+        }
+
+        Ok(fetcher)
+    }
+
+    fn fetch(&mut self) -> Result<Vec<u8>, Error> {
+        let num_pieces = (self.metadata_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+        let mut info_bytes = vec![];
+        for piece in 0..num_pieces {
+            if self.done.load(Ordering::SeqCst) {
+                return Err(Error::Cancelled);
+            }
+            let id = self.their_ut_metadata_id;
+            try!(self.send_extended(id, &try!(request_message(piece))));
+
+            // the payload is a bencoded dictionary, followed by the piece data
+            let payload = try!(self.receive_extended(extension::UT_METADATA_ID));
+            let dict_length = match decoder::bencoded_length(&payload) {
+                Some(l) => l,
+                None => return Err(Error::InvalidMessage)
+            };
+            let (msg_type, their_piece) = try!(parse_message_header(&payload[..dict_length]));
+            if msg_type == MSG_TYPE_REJECT {
+                return Err(Error::MetadataRejected);
+            } else if msg_type != MSG_TYPE_DATA || their_piece != piece {
+                return Err(Error::InvalidMessage);
+            }
+            info_bytes.extend(payload[dict_length..].iter().cloned());
+        }
+
+        // make sure we got what we asked for
+        if info_bytes.len() != self.metadata_size as usize || calculate_sha1(&info_bytes) != self.info_hash {
+            return Err(Error::InvalidMetadata);
+        }
+        println!("Received metadata");
+        Ok(info_bytes)
+    }
+
+    fn send_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut message = u32_to_bytes(payload.len() as u32 + 2);
+        message.push(EXTENDED_MESSAGE_ID);
+        message.push(id);
+        message.extend(payload.iter().cloned());
+        try!(self.stream.write_all(&message));
+        Ok(())
+    }
+
+    // skip over any other messages the peer sends (bitfields, haves, etc) until we get the extended message we want
+    fn receive_extended(&mut self, id: u8) -> Result<Vec<u8>, Error> {
+        loop {
+            let message_size = bytes_to_u32(&try!(read_n(&mut self.stream, 4)));
+            if message_size < 2 {
+                try!(read_n(&mut self.stream, message_size));
+                continue;
+            }
+
+            let header = try!(read_n(&mut self.stream, 2));
+            let payload_size = message_size - 2;
+            if header[0] == EXTENDED_MESSAGE_ID && header[1] == id {
+                if payload_size > MAX_EXTENDED_MESSAGE_SIZE {
+                    return Err(Error::MessageTooLarge(message_size));
+                }
+                return Ok(try!(read_n(&mut self.stream, payload_size)));
+            }
+
+            // throw away anything else as it arrives, rather than holding however much the peer says it's sending
+            try!(io::copy(&mut (&self.stream).take(payload_size as u64), &mut io::sink()));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidInfoHash,
+    ExtensionsNotSupported,
+    MetadataNotSupported,
+    MetadataRejected,
+    MetadataUnavailable,
+    InvalidMessage,
+    InvalidMetadata,
+    MessageTooLarge(u32),
+    Cancelled,
+    DecoderError(decoder::Error),
+    PeerConnectionError(peer_connection::Error),
+    IoError(io::Error),
+}
+
+impl convert::From<decoder::Error> for Error {
+    fn from(err: decoder::Error) -> Error {
+        Error::DecoderError(err)
+    }
+}
+
+impl convert::From<peer_connection::Error> for Error {
+    fn from(err: peer_connection::Error) -> Error {
+        Error::PeerConnectionError(err)
+    }
+}
+
+impl convert::From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}